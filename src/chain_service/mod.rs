@@ -1,105 +1,201 @@
 mod model;
+pub mod probe;
 
 use bytes::{Buf, Bytes};
 use http_body_util::{BodyExt, Empty, Full};
 use hyper::{
     header::{self},
+    http::request,
     Method, Request,
 };
 use hyper_tls::HttpsConnector;
 use hyper_util::client::legacy::Client;
 use model::{
-    AptosBlock, BitcoinBlock, CosmosBlockResponse, JSONRPCRequest, JSONRPCResponse, NearBlock,
-    TonBlock, TronBlock, XRPBlock,
+    AlgorandStatus, AptosBlock, AptosLedgerInfo, BitcoinBlock, CardanoBlock, CosmosBlockResponse,
+    CosmosNodeInfo, CosmosSyncing, JSONRPCRequest, JSONRPCResponse, NearBlock, StellarRoot,
+    SubstrateBlockHeader, TonBlock, TronBlock, TronBlockId, XRPBlock,
 };
+use std::collections::HashMap;
 use serde::de::DeserializeOwned;
 use serde_json::Value;
 
 use serde_json::to_vec;
-use primitives::ChainType;
+use probe::{ChainIdentityMethod, HealthCheck, HealthSignal, Probe, ProbePreset, RestMethod};
 
 pub struct ChainService {
-    pub chain_type: ChainType,
+    pub probe: Probe,
     pub url: String,
+    pub headers: HashMap<String, String>,
 }
 
 impl ChainService {
     pub async fn get_block_number(&self) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
-        match self.chain_type {
-            ChainType::Ethereum => {
-                let block_hex = self
-                    .get_json_rpc_data::<JSONRPCResponse<String>>("eth_blockNumber", None)
+        match &self.probe {
+            Probe::Preset { name } => self.get_preset_block_number(*name).await,
+            Probe::JsonRpc {
+                method,
+                params,
+                pointer,
+                format,
+            } => {
+                let value = self
+                    .get_json_rpc_data::<Value>(method, params.clone())
+                    .await?;
+                probe::parse_block_number(&value, pointer, *format)
+            }
+            Probe::Rest {
+                method,
+                path,
+                pointer,
+                format,
+            } => {
+                let method = match method {
+                    RestMethod::Get => Method::GET,
+                    RestMethod::Post => Method::POST,
+                };
+                let value = self.get_data::<Value>(method, path).await?;
+                probe::parse_block_number(&value, pointer, *format)
+            }
+        }
+    }
+
+    async fn get_preset_block_number(
+        &self,
+        preset: ProbePreset,
+    ) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+        let value = match preset {
+            ProbePreset::Ethereum | ProbePreset::Hyperliquid => {
+                self.get_json_rpc_data::<Value>("eth_blockNumber", None)
+                    .await?
+            }
+            ProbePreset::Bitcoin => self.get_data::<Value>(Method::GET, "/api/").await?,
+            ProbePreset::Solana => self.get_json_rpc_data::<Value>("getSlot", None).await?,
+            ProbePreset::Cosmos => {
+                self.get_data::<Value>(Method::GET, "/cosmos/base/tendermint/v1beta1/blocks/latest")
+                    .await?
+            }
+            ProbePreset::Ton => {
+                self.get_data::<Value>(Method::GET, "/api/v2/getConsensusBlock")
+                    .await?
+            }
+            ProbePreset::Tron => {
+                self.get_data::<Value>(Method::POST, "/wallet/getnowblock")
+                    .await?
+            }
+            ProbePreset::Aptos => self.get_data::<Value>(Method::GET, "/v1/").await?,
+            ProbePreset::Sui => {
+                self.get_json_rpc_data::<Value>("sui_getLatestCheckpointSequenceNumber", None)
+                    .await?
+            }
+            ProbePreset::Xrp => {
+                self.get_json_rpc_data::<Value>("ledger_current", None)
+                    .await?
+            }
+            ProbePreset::Near => {
+                let params = serde_json::json!({"finality": "final"});
+                self.get_json_rpc_data::<Value>("block", Some(params))
+                    .await?
+            }
+            ProbePreset::Cardano => {
+                self.get_data::<Value>(Method::GET, get_cardano_blocks_path(&self.url))
+                    .await?
+            }
+            ProbePreset::CardanoOgmios => {
+                self.get_json_rpc_data::<Value>("queryNetwork/blockHeight", None)
+                    .await?
+            }
+            ProbePreset::Polkadot => {
+                self.get_json_rpc_data::<Value>("chain_getHeader", None)
+                    .await?
+            }
+            ProbePreset::Stellar => self.get_data::<Value>(Method::GET, "/").await?,
+            ProbePreset::Algorand => self.get_data::<Value>(Method::GET, "/v2/status").await?,
+            ProbePreset::Esplora => {
+                self.get_data::<Value>(Method::GET, "/api/blocks/tip/height")
+                    .await?
+            }
+            ProbePreset::BitcoinRpc => {
+                self.get_json_rpc_data::<Value>("getblockcount", None)
                     .await?
-                    .result;
-                Ok(u64::from_str_radix(&block_hex[2..], 16)?)
             }
-            ChainType::Bitcoin => Ok(self
+        };
+        parse_preset_block_number(preset, value)
+    }
+
+    pub async fn get_health_signals(&self, checks: &[HealthCheck]) -> Vec<HealthSignal> {
+        let signals = checks.iter().map(|check| async move {
+            let healthy = self.is_healthy(*check).await.unwrap_or(false);
+            HealthSignal {
+                check: *check,
+                healthy,
+            }
+        });
+        futures::future::join_all(signals).await
+    }
+
+    async fn is_healthy(
+        &self,
+        check: HealthCheck,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        match check {
+            HealthCheck::EthSyncing => Ok(self
+                .get_json_rpc_data::<JSONRPCResponse<Value>>("eth_syncing", None)
+                .await?
+                .result
+                == Value::Bool(false)),
+            HealthCheck::SolanaHealth => Ok(self
+                .get_json_rpc_data::<JSONRPCResponse<String>>("getHealth", None)
+                .await?
+                .result
+                == "ok"),
+            HealthCheck::CosmosSyncing => Ok(!self
+                .get_data::<CosmosSyncing>(Method::GET, "/cosmos/base/tendermint/v1beta1/syncing")
+                .await?
+                .syncing),
+            HealthCheck::BlockbookInSync => Ok(self
                 .get_data::<BitcoinBlock>(Method::GET, "/api/")
                 .await?
                 .blockbook
-                .best_height),
-            ChainType::Solana => Ok(self
-                .get_json_rpc_data::<JSONRPCResponse<u64>>("getSlot", None)
+                .in_sync
+                .unwrap_or(false)),
+            HealthCheck::ChainId | HealthCheck::ErrorResponses => Ok(true),
+        }
+    }
+
+    pub async fn get_chain_identity(
+        &self,
+        method: ChainIdentityMethod,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        match method {
+            ChainIdentityMethod::EthChainId => Ok(self
+                .get_json_rpc_data::<JSONRPCResponse<String>>("eth_chainId", None)
                 .await?
                 .result),
-            ChainType::Cosmos => Ok(self
-                .get_data::<CosmosBlockResponse>(
+            ChainIdentityMethod::SolanaGenesisHash => Ok(self
+                .get_json_rpc_data::<JSONRPCResponse<String>>("getGenesisHash", None)
+                .await?
+                .result),
+            ChainIdentityMethod::CosmosChainId => Ok(self
+                .get_data::<CosmosNodeInfo>(
                     Method::GET,
-                    "/cosmos/base/tendermint/v1beta1/blocks/latest",
+                    "/cosmos/base/tendermint/v1beta1/node_info",
                 )
                 .await?
-                .block
-                .header
-                .height
-                .parse::<u64>()
-                .expect("number should be a u64")),
-            ChainType::Ton => Ok(self
-                .get_data::<JSONRPCResponse<TonBlock>>(Method::GET, "/api/v2/getConsensusBlock")
+                .default_node_info
+                .network),
+            ChainIdentityMethod::SuiChainIdentifier => Ok(self
+                .get_json_rpc_data::<JSONRPCResponse<String>>("sui_getChainIdentifier", None)
                 .await?
-                .result
-                .consensus_block),
-            ChainType::Tron => Ok(self
-                .get_data::<TronBlock>(Method::POST, "/wallet/getnowblock")
+                .result),
+            ChainIdentityMethod::AptosChainId => Ok(self
+                .get_data::<AptosLedgerInfo>(Method::GET, "/v1/")
                 .await?
-                .block_header
-                .raw_data
-                .number),
-            ChainType::Aptos => Ok(self
-                .get_data::<AptosBlock>(Method::GET, "/v1/")
+                .chain_id
+                .to_string()),
+            ChainIdentityMethod::TronGenesisBlock => Ok(self
+                .post_data::<TronBlockId>("/wallet/getblockbynum", serde_json::json!({"num": 0}))
                 .await?
-                .block_height
-                .parse::<u64>()
-                .expect("number should be a u64")),
-            ChainType::Sui => {
-                let block = self
-                    .get_json_rpc_data::<JSONRPCResponse<String>>(
-                        "sui_getLatestCheckpointSequenceNumber",
-                        None,
-                    )
-                    .await?
-                    .result
-                    .parse::<u64>()
-                    .expect("number should be a u64");
-                Ok(block)
-            }
-            ChainType::Xrp => {
-                let block = self
-                    .get_json_rpc_data::<JSONRPCResponse<XRPBlock>>("ledger_current", None)
-                    .await?
-                    .result;
-                Ok(block.ledger_current_index)
-            }
-            ChainType::Near => {
-                let data = r#"{"finality": "final"}"#;
-                let params: Value = serde_json::from_str(data)?;
-                let block = self
-                    .get_json_rpc_data::<JSONRPCResponse<NearBlock>>("block", Some(params))
-                    .await?
-                    .result
-                    .header
-                    .height;
-                Ok(block)
-            }
+                .block_id),
         }
     }
 
@@ -110,7 +206,7 @@ impl ChainService {
     ) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
         let client =
             Client::builder(hyper_util::rt::TokioExecutor::new()).build(HttpsConnector::new());
-        let uri = self.url.parse::<hyper::Uri>().expect("invalid url");
+        let uri = self.url.parse::<hyper::Uri>()?;
 
         let payload = JSONRPCRequest {
             id: 1,
@@ -122,11 +218,7 @@ impl ChainService {
         let json_bytes = to_vec(&payload)?;
         let body = Full::new(Bytes::from(json_bytes));
 
-        let req = Request::builder()
-            .method(Method::POST)
-            .header(header::CONTENT_TYPE, "application/json")
-            .uri(uri)
-            .body(body)?;
+        let req = self.request_builder(Method::POST, uri).body(body)?;
 
         let res = client.request(req).await?;
         let body = res.collect().await?.to_bytes();
@@ -141,13 +233,11 @@ impl ChainService {
     ) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
         let client =
             Client::builder(hyper_util::rt::TokioExecutor::new()).build(HttpsConnector::new());
-        let uri = self.url.clone() + path;
-        let uri = uri.parse::<hyper::Uri>().expect("invalid url");
+        let uri = self.url.trim_end_matches('/').to_string() + path;
+        let uri = uri.parse::<hyper::Uri>()?;
 
-        let req = Request::builder()
-            .method(method)
-            .header(header::CONTENT_TYPE, "application/json")
-            .uri(uri)
+        let req = self
+            .request_builder(method, uri)
             .body(Empty::<Bytes>::new())?;
 
         let res = client.request(req).await?;
@@ -155,4 +245,203 @@ impl ChainService {
 
         Ok(serde_json::from_reader(body.reader())?)
     }
+
+    pub async fn post_data<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: Value,
+    ) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+        let client =
+            Client::builder(hyper_util::rt::TokioExecutor::new()).build(HttpsConnector::new());
+        let uri = (self.url.trim_end_matches('/').to_string() + path).parse::<hyper::Uri>()?;
+
+        let body = Full::new(Bytes::from(to_vec(&body)?));
+        let req = self.request_builder(Method::POST, uri).body(body)?;
+
+        let res = client.request(req).await?;
+        let body = res.collect().await?.to_bytes();
+
+        Ok(serde_json::from_reader(body.reader())?)
+    }
+
+    fn request_builder(&self, method: Method, uri: hyper::Uri) -> request::Builder {
+        self.headers.iter().fold(
+            Request::builder()
+                .method(method)
+                .header(header::CONTENT_TYPE, "application/json")
+                .uri(uri),
+            |builder, (key, value)| builder.header(key, value),
+        )
+    }
+}
+
+// Blockfrost base urls already carry the /api/v0 prefix, self hosted APIs are mounted at the root
+fn get_cardano_blocks_path(url: &str) -> &'static str {
+    if url.trim_end_matches('/').ends_with("/api/v0") {
+        "/blocks/latest"
+    } else {
+        "/api/v0/blocks/latest"
+    }
+}
+
+// Kept apart from the requests so fixtures can exercise it, malformed answers are errors
+fn parse_preset_block_number(
+    preset: ProbePreset,
+    value: Value,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let number = match preset {
+        ProbePreset::Ethereum | ProbePreset::Hyperliquid => {
+            parse_hex(&serde_json::from_value::<JSONRPCResponse<String>>(value)?.result)?
+        }
+        ProbePreset::Bitcoin => {
+            serde_json::from_value::<BitcoinBlock>(value)?
+                .blockbook
+                .best_height
+        }
+        ProbePreset::Solana | ProbePreset::CardanoOgmios | ProbePreset::BitcoinRpc => {
+            serde_json::from_value::<JSONRPCResponse<u64>>(value)?.result
+        }
+        ProbePreset::Cosmos => serde_json::from_value::<CosmosBlockResponse>(value)?
+            .block
+            .header
+            .height
+            .parse::<u64>()?,
+        ProbePreset::Ton => {
+            serde_json::from_value::<JSONRPCResponse<TonBlock>>(value)?
+                .result
+                .consensus_block
+        }
+        ProbePreset::Tron => {
+            serde_json::from_value::<TronBlock>(value)?
+                .block_header
+                .raw_data
+                .number
+        }
+        ProbePreset::Aptos => serde_json::from_value::<AptosBlock>(value)?
+            .block_height
+            .parse::<u64>()?,
+        ProbePreset::Sui => serde_json::from_value::<JSONRPCResponse<String>>(value)?
+            .result
+            .parse::<u64>()?,
+        ProbePreset::Xrp => {
+            serde_json::from_value::<JSONRPCResponse<XRPBlock>>(value)?
+                .result
+                .ledger_current_index
+        }
+        ProbePreset::Near => {
+            serde_json::from_value::<JSONRPCResponse<NearBlock>>(value)?
+                .result
+                .header
+                .height
+        }
+        ProbePreset::Cardano => serde_json::from_value::<CardanoBlock>(value)?.height,
+        ProbePreset::Polkadot => parse_hex(
+            &serde_json::from_value::<JSONRPCResponse<SubstrateBlockHeader>>(value)?
+                .result
+                .number,
+        )?,
+        ProbePreset::Stellar => serde_json::from_value::<StellarRoot>(value)?.history_latest_ledger,
+        ProbePreset::Algorand => serde_json::from_value::<AlgorandStatus>(value)?.last_round,
+        ProbePreset::Esplora => serde_json::from_value::<u64>(value)?,
+    };
+    Ok(number)
+}
+
+fn parse_hex(value: &str) -> Result<u64, std::num::ParseIntError> {
+    u64::from_str_radix(value.trim_start_matches("0x"), 16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_fixture(preset: ProbePreset, fixture: &str) -> u64 {
+        parse_preset_block_number(preset, serde_json::from_str(fixture).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_preset_block_number() {
+        let cases = [
+            (
+                ProbePreset::Cardano,
+                include_str!("fixtures/cardano_blocks_latest.json"),
+                11_412_753,
+            ),
+            (
+                ProbePreset::CardanoOgmios,
+                include_str!("fixtures/cardano_ogmios_block_height.json"),
+                11_412_753,
+            ),
+            (
+                ProbePreset::Polkadot,
+                include_str!("fixtures/polkadot_chain_get_header.json"),
+                0x1699f1e,
+            ),
+            (
+                ProbePreset::Stellar,
+                include_str!("fixtures/stellar_root.json"),
+                54_961_423,
+            ),
+            (
+                ProbePreset::Algorand,
+                include_str!("fixtures/algorand_status.json"),
+                45_210_034,
+            ),
+            (
+                ProbePreset::Esplora,
+                include_str!("fixtures/esplora_tip_height.json"),
+                868_421,
+            ),
+            (
+                ProbePreset::BitcoinRpc,
+                include_str!("fixtures/bitcoin_rpc_get_block_count.json"),
+                868_421,
+            ),
+            (
+                ProbePreset::Hyperliquid,
+                include_str!("fixtures/hyperliquid_eth_block_number.json"),
+                10_107_999,
+            ),
+        ];
+        for (preset, fixture, expected) in cases {
+            assert_eq!(parse_fixture(preset, fixture), expected, "{:?}", preset);
+        }
+    }
+
+    #[test]
+    fn test_parse_preset_block_number_malformed() {
+        let parse = |preset, value| parse_preset_block_number(preset, value).is_err();
+        assert!(parse(
+            ProbePreset::Ethereum,
+            serde_json::json!({"jsonrpc": "2.0", "id": 1, "result": "0x"})
+        ));
+        assert!(parse(
+            ProbePreset::Ethereum,
+            serde_json::json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32000}})
+        ));
+        assert!(parse(
+            ProbePreset::Sui,
+            serde_json::json!({"jsonrpc": "2.0", "id": 1, "result": "abc"})
+        ));
+        assert!(parse(
+            ProbePreset::Cosmos,
+            serde_json::json!({"block": {"header": {"height": ""}}})
+        ));
+    }
+
+    #[test]
+    fn test_cardano_blocks_path() {
+        assert_eq!(
+            get_cardano_blocks_path("https://cardano-mainnet.blockfrost.io/api/v0"),
+            "/blocks/latest"
+        );
+        assert_eq!(
+            get_cardano_blocks_path("https://cardano-mainnet.blockfrost.io/api/v0/"),
+            "/blocks/latest"
+        );
+        assert_eq!(
+            get_cardano_blocks_path("http://localhost:3000"),
+            "/api/v0/blocks/latest"
+        );
+    }
 }
@@ -39,6 +39,17 @@ pub struct AptosBlock {
     pub block_height: String,
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AptosLedgerInfo {
+    pub chain_id: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TronBlockId {
+    #[serde(rename = "blockID")]
+    pub block_id: String,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct TronBlock {
     pub block_header: TronBlockHeader,
@@ -77,4 +88,42 @@ pub struct BitcoinBlock {
 pub struct BitcoinBlockbook {
     #[serde(rename = "bestHeight")]
     pub best_height: u64,
+    #[serde(rename = "inSync")]
+    pub in_sync: Option<bool>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CosmosNodeInfo {
+    pub default_node_info: CosmosDefaultNodeInfo,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CosmosDefaultNodeInfo {
+    pub network: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CosmosSyncing {
+    pub syncing: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CardanoBlock {
+    pub height: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SubstrateBlockHeader {
+    pub number: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct StellarRoot {
+    pub history_latest_ledger: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AlgorandStatus {
+    #[serde(rename = "last-round")]
+    pub last_round: u64,
 }
@@ -0,0 +1,241 @@
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use primitives::ChainType;
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Probe {
+    Preset {
+        name: ProbePreset,
+    },
+    JsonRpc {
+        method: String,
+        params: Option<Value>,
+        pointer: String,
+        format: Option<NumberFormat>,
+    },
+    Rest {
+        #[serde(default)]
+        method: RestMethod,
+        path: String,
+        pointer: String,
+        format: Option<NumberFormat>,
+    },
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbePreset {
+    Ethereum,
+    Bitcoin,
+    Solana,
+    Cosmos,
+    Ton,
+    Tron,
+    Aptos,
+    Sui,
+    Xrp,
+    Near,
+    Cardano,
+    CardanoOgmios,
+    Polkadot,
+    Stellar,
+    Algorand,
+    Hyperliquid,
+    Esplora,
+    BitcoinRpc,
+}
+
+impl ProbePreset {
+    pub fn from_chain_type(chain_type: &str) -> Option<Self> {
+        let Ok(chain_type) = ChainType::from_str(chain_type) else {
+            return serde_json::from_value(Value::String(chain_type.to_string())).ok();
+        };
+        let preset = match chain_type {
+            ChainType::Ethereum => Self::Ethereum,
+            ChainType::Bitcoin => Self::Bitcoin,
+            ChainType::Solana => Self::Solana,
+            ChainType::Cosmos => Self::Cosmos,
+            ChainType::Ton => Self::Ton,
+            ChainType::Tron => Self::Tron,
+            ChainType::Aptos => Self::Aptos,
+            ChainType::Sui => Self::Sui,
+            ChainType::Xrp => Self::Xrp,
+            ChainType::Near => Self::Near,
+        };
+        Some(preset)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheck {
+    EthSyncing,
+    SolanaHealth,
+    CosmosSyncing,
+    BlockbookInSync,
+    #[serde(skip_deserializing)]
+    ChainId,
+    #[serde(skip_deserializing)]
+    ErrorResponses,
+}
+
+impl HealthCheck {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::EthSyncing => "eth_syncing",
+            Self::SolanaHealth => "solana_health",
+            Self::CosmosSyncing => "cosmos_syncing",
+            Self::BlockbookInSync => "blockbook_in_sync",
+            Self::ChainId => "chain_id",
+            Self::ErrorResponses => "error_responses",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthSignal {
+    pub check: HealthCheck,
+    pub healthy: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct ChainIdentity {
+    pub method: Option<ChainIdentityMethod>,
+    pub expected: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChainIdentityMethod {
+    EthChainId,
+    SolanaGenesisHash,
+    CosmosChainId,
+    SuiChainIdentifier,
+    AptosChainId,
+    TronGenesisBlock,
+}
+
+impl ChainIdentityMethod {
+    pub fn from_preset(preset: ProbePreset) -> Option<Self> {
+        match preset {
+            ProbePreset::Ethereum | ProbePreset::Hyperliquid => Some(Self::EthChainId),
+            ProbePreset::Solana => Some(Self::SolanaGenesisHash),
+            ProbePreset::Cosmos => Some(Self::CosmosChainId),
+            ProbePreset::Sui => Some(Self::SuiChainIdentifier),
+            ProbePreset::Aptos => Some(Self::AptosChainId),
+            ProbePreset::Tron => Some(Self::TronGenesisBlock),
+            _ => None,
+        }
+    }
+
+    pub fn matches(&self, expected: &str, actual: &str) -> bool {
+        match self {
+            Self::EthChainId => {
+                parse_chain_id(expected).is_some_and(|x| Some(x) == parse_chain_id(actual))
+            }
+            _ => expected == actual,
+        }
+    }
+}
+
+fn parse_chain_id(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse::<u64>().ok(),
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum RestMethod {
+    #[default]
+    Get,
+    Post,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NumberFormat {
+    Hex,
+    Decimal,
+    String,
+}
+
+pub fn parse_block_number(
+    value: &Value,
+    pointer: &str,
+    format: Option<NumberFormat>,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let value = value
+        .pointer(pointer)
+        .ok_or_else(|| format!("no value at pointer {}", pointer))?;
+
+    let number = match (format, value) {
+        (Some(NumberFormat::Decimal) | None, Value::Number(number)) => number.as_u64(),
+        (Some(NumberFormat::Hex), Value::String(string)) => {
+            u64::from_str_radix(string.trim_start_matches("0x"), 16).ok()
+        }
+        (Some(NumberFormat::String), Value::String(string)) => string.parse::<u64>().ok(),
+        (None, Value::String(string)) => match string.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => string.parse::<u64>().ok(),
+        },
+        _ => None,
+    };
+    number.ok_or_else(|| format!("invalid block number at pointer {}: {}", pointer, value).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_preset_from_chain_type() {
+        assert_eq!(
+            ProbePreset::from_chain_type("ethereum"),
+            Some(ProbePreset::Ethereum)
+        );
+        assert_eq!(
+            ProbePreset::from_chain_type("cardano_ogmios"),
+            Some(ProbePreset::CardanoOgmios)
+        );
+        assert_eq!(ProbePreset::from_chain_type("unknown"), None);
+    }
+
+    #[test]
+    fn test_chain_identity_matches() {
+        assert!(ChainIdentityMethod::EthChainId.matches("1", "0x1"));
+        assert!(ChainIdentityMethod::EthChainId.matches("0x38", "0x38"));
+        assert!(!ChainIdentityMethod::EthChainId.matches("1", "0xaa36a7"));
+        assert!(ChainIdentityMethod::SuiChainIdentifier.matches("35834a8a", "35834a8a"));
+        assert!(!ChainIdentityMethod::SolanaGenesisHash.matches("5eykt4", "EtWTRA"));
+    }
+
+    #[test]
+    fn test_parse_block_number() {
+        let value = json!({"result": "0x1b4", "height": "436", "number": 436});
+
+        assert_eq!(parse_block_number(&value, "/result", None).unwrap(), 436);
+        assert_eq!(
+            parse_block_number(&value, "/result", Some(NumberFormat::Hex)).unwrap(),
+            436
+        );
+        assert_eq!(parse_block_number(&value, "/height", None).unwrap(), 436);
+        assert_eq!(
+            parse_block_number(&value, "/height", Some(NumberFormat::String)).unwrap(),
+            436
+        );
+        assert_eq!(
+            parse_block_number(&value, "/number", Some(NumberFormat::Decimal)).unwrap(),
+            436
+        );
+
+        assert!(parse_block_number(&value, "/number", Some(NumberFormat::Hex)).is_err());
+        assert!(parse_block_number(&value, "/missing", None).is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::HeaderMap;
+
+use crate::config::ForwardedHeader;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_REAL_IP: &str = "x-real-ip";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = value.split_once('/').unwrap_or((value, ""));
+        let address = IpAddr::from_str(address)
+            .map_err(|_| format!("invalid trusted proxy {}", value))?
+            .to_canonical();
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => max_prefix,
+            prefix => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|x| *x <= max_prefix)
+                .ok_or(format!("invalid trusted proxy prefix {}", value))?,
+        };
+        Ok(Self { address, prefix })
+    }
+}
+
+// Peers allowed to report the client address through forwarding headers
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Arc<Vec<Network>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientAddress {
+    // client as reported by trusted proxies, otherwise the peer
+    pub ip: IpAddr,
+    pub remote_ip: IpAddr,
+    pub proto: String,
+    // incoming chains, only kept when the peer is trusted
+    pub forwarded_for: Option<String>,
+    pub forwarded: Option<String>,
+}
+
+fn get_header(headers: &HeaderMap, name: &str) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+// Accepts bare addresses, "[v6]:port", "v4:port" and quoted forms
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    let ip = match value.strip_prefix('[') {
+        Some(value) => IpAddr::from_str(value.split(']').next()?).ok(),
+        None => IpAddr::from_str(value)
+            .ok()
+            .or_else(|| SocketAddr::from_str(value).ok().map(|x| x.ip())),
+    };
+    ip.map(|x| x.to_canonical())
+}
+
+// Values of one parameter, such as "for" or "proto", across Forwarded elements
+fn get_forwarded_params(forwarded: &str, name: &str) -> Vec<String> {
+    forwarded
+        .split(',')
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case(name)
+                    .then(|| value.trim_matches('"').to_string())
+            })
+        })
+        .collect()
+}
+
+impl TrustedProxies {
+    pub fn new(cidrs: &[String]) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let networks = cidrs
+            .iter()
+            .map(|x| Network::from_str(x))
+            .collect::<Result<Vec<Network>, String>>()?;
+        Ok(Self {
+            networks: Arc::new(networks),
+        })
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.networks.iter().any(|x| x.contains(ip))
+    }
+
+    pub fn get_client_address(&self, remote_ip: IpAddr, headers: &HeaderMap) -> ClientAddress {
+        let remote_ip = remote_ip.to_canonical();
+        let mut address = ClientAddress {
+            ip: remote_ip,
+            remote_ip,
+            proto: "http".to_string(),
+            forwarded_for: None,
+            forwarded: None,
+        };
+        if !self.is_trusted(remote_ip) {
+            return address;
+        }
+
+        address.forwarded_for = get_header(headers, X_FORWARDED_FOR);
+        address.forwarded = get_header(headers, header::FORWARDED.as_str());
+
+        let chain: Vec<String> = match (&address.forwarded_for, &address.forwarded) {
+            (Some(forwarded_for), _) => forwarded_for.split(',').map(|x| x.to_string()).collect(),
+            (None, Some(forwarded)) => get_forwarded_params(forwarded, "for"),
+            (None, None) => get_header(headers, X_REAL_IP).into_iter().collect(),
+        };
+        // rightmost hop not operated by a trusted proxy, later hops were appended by them
+        for hop in chain.iter().rev() {
+            let Some(ip) = parse_ip(hop) else {
+                break;
+            };
+            address.ip = ip;
+            if !self.is_trusted(ip) {
+                break;
+            }
+        }
+
+        let proto = match get_header(headers, X_FORWARDED_PROTO) {
+            Some(proto) => proto.rsplit(',').next().map(|x| x.trim().to_lowercase()),
+            None => address
+                .forwarded
+                .as_ref()
+                .and_then(|x| get_forwarded_params(x, "proto").pop()),
+        };
+        if let Some(proto) = proto.filter(|x| x == "http" || x == "https") {
+            address.proto = proto;
+        }
+        address
+    }
+}
+
+impl ClientAddress {
+    // The hop added here describes the peer, trusted chains are extended rather than replaced
+    pub fn get_forwarded_headers(&self, host: &str, enabled: &[ForwardedHeader]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for header in enabled {
+            let (name, value) = match header {
+                ForwardedHeader::XForwardedFor => (
+                    HeaderName::from_static(X_FORWARDED_FOR),
+                    match &self.forwarded_for {
+                        Some(chain) => format!("{}, {}", chain, self.remote_ip),
+                        None => self.remote_ip.to_string(),
+                    },
+                ),
+                ForwardedHeader::XForwardedProto => (
+                    HeaderName::from_static(X_FORWARDED_PROTO),
+                    self.proto.clone(),
+                ),
+                ForwardedHeader::XRealIp => {
+                    (HeaderName::from_static(X_REAL_IP), self.ip.to_string())
+                }
+                ForwardedHeader::Forwarded => {
+                    let node = match self.remote_ip {
+                        IpAddr::V4(ip) => ip.to_string(),
+                        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+                    };
+                    let element = format!("for={};proto={};host=\"{}\"", node, self.proto, host);
+                    let value = match &self.forwarded {
+                        Some(chain) => format!("{}, {}", chain, element),
+                        None => element,
+                    };
+                    (header::FORWARDED, value)
+                }
+            };
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(name, value);
+            }
+        }
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::headers;
+
+    fn ip(value: &str) -> IpAddr {
+        IpAddr::from_str(value).unwrap()
+    }
+
+    #[test]
+    fn test_network_contains() {
+        let network = Network::from_str("10.0.0.0/8").unwrap();
+        assert!(network.contains(ip("10.1.2.3")));
+        assert!(!network.contains(ip("11.0.0.1")));
+        assert!(Network::from_str("::1").unwrap().contains(ip("::1")));
+        assert!(Network::from_str("0.0.0.0/0")
+            .unwrap()
+            .contains(ip("8.8.8.8")));
+        assert!(Network::from_str("10.0.0.0/33").is_err());
+    }
+
+    #[test]
+    fn test_untrusted_peer() {
+        let proxies = TrustedProxies::new(&["10.0.0.0/8".to_string()]).unwrap();
+        let address = proxies.get_client_address(
+            ip("203.0.113.7"),
+            &headers(&[
+                ("x-forwarded-for", "1.1.1.1"),
+                ("x-forwarded-proto", "https"),
+            ]),
+        );
+        assert_eq!(address.ip, ip("203.0.113.7"));
+        assert_eq!(address.proto, "http");
+        assert_eq!(address.forwarded_for, None);
+    }
+
+    #[test]
+    fn test_trusted_peer() {
+        let proxies = TrustedProxies::new(&["10.0.0.0/8".to_string()]).unwrap();
+        let address = proxies.get_client_address(
+            ip("10.0.0.1"),
+            &headers(&[
+                ("x-forwarded-for", "1.1.1.1, 203.0.113.7, 10.0.0.2"),
+                ("x-forwarded-proto", "https"),
+            ]),
+        );
+        assert_eq!(address.ip, ip("203.0.113.7"));
+        assert_eq!(address.proto, "https");
+
+        let address = proxies.get_client_address(
+            ip("::ffff:10.0.0.1"),
+            &headers(&[(
+                "forwarded",
+                "for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.2",
+            )]),
+        );
+        assert_eq!(address.ip, ip("2001:db8::1"));
+        assert_eq!(address.remote_ip, ip("10.0.0.1"));
+        assert_eq!(address.proto, "https");
+    }
+
+    #[test]
+    fn test_get_forwarded_headers() {
+        let proxies = TrustedProxies::new(&["10.0.0.0/8".to_string()]).unwrap();
+        let address = proxies.get_client_address(
+            ip("10.0.0.1"),
+            &headers(&[("x-forwarded-for", "203.0.113.7")]),
+        );
+        let forwarded = address.get_forwarded_headers(
+            "localhost:3000",
+            &[
+                ForwardedHeader::XForwardedFor,
+                ForwardedHeader::XForwardedProto,
+                ForwardedHeader::XRealIp,
+                ForwardedHeader::Forwarded,
+            ],
+        );
+        assert_eq!(
+            forwarded,
+            headers(&[
+                ("x-forwarded-for", "203.0.113.7, 10.0.0.1"),
+                ("x-forwarded-proto", "http"),
+                ("x-real-ip", "203.0.113.7"),
+                (
+                    "forwarded",
+                    "for=10.0.0.1;proto=http;host=\"localhost:3000\""
+                ),
+            ])
+        );
+    }
+}
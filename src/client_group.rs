@@ -0,0 +1,126 @@
+use std::error::Error;
+use std::str::FromStr;
+
+use hyper::header::{self, HeaderName};
+use hyper::HeaderMap;
+use regex::Regex;
+
+use crate::config::ClientGroup;
+
+const OTHER_CLIENT_GROUP: &str = "other";
+
+#[derive(Debug, Clone)]
+struct ClientGroupMatcher {
+    name: String,
+    user_agent: Option<Regex>,
+    header: Option<HeaderName>,
+    value: Option<Regex>,
+}
+
+impl ClientGroupMatcher {
+    fn matches(&self, headers: &HeaderMap) -> bool {
+        let get = |name: &HeaderName| headers.get(name).and_then(|x| x.to_str().ok());
+        let user_agent = match &self.user_agent {
+            Some(x) => get(&header::USER_AGENT).is_some_and(|value| x.is_match(value)),
+            None => true,
+        };
+        let header = match (&self.header, &self.value) {
+            (Some(name), Some(x)) => get(name).is_some_and(|value| x.is_match(value)),
+            (Some(name), None) => get(name).is_some(),
+            (None, _) => true,
+        };
+        user_agent && header
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ClientGroups {
+    groups: Vec<ClientGroupMatcher>,
+}
+
+impl ClientGroups {
+    pub fn new(config: &[ClientGroup]) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let regex = |name: &str, pattern: &Option<String>| {
+            pattern
+                .as_ref()
+                .map(|x| Regex::new(x))
+                .transpose()
+                .map_err(|err| format!("invalid client group pattern for {}: {}", name, err))
+        };
+        let groups = config
+            .iter()
+            .map(|group| {
+                if group.user_agent.is_none() && group.header.is_none() {
+                    return Err(format!(
+                        "client group {} needs a user_agent or header condition",
+                        group.name
+                    ));
+                }
+                let header = group
+                    .header
+                    .as_ref()
+                    .map(|x| HeaderName::from_str(x))
+                    .transpose()
+                    .map_err(|err| {
+                        format!("invalid client group header for {}: {}", group.name, err)
+                    })?;
+                Ok(ClientGroupMatcher {
+                    name: group.name.clone(),
+                    user_agent: regex(&group.name, &group.user_agent)?,
+                    header,
+                    value: regex(&group.name, &group.value)?,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self { groups })
+    }
+
+    // Empty when no groups are configured, "other" when none match
+    pub fn get_group(&self, headers: &HeaderMap) -> String {
+        if self.groups.is_empty() {
+            return String::new();
+        }
+        self.groups
+            .iter()
+            .find(|x| x.matches(headers))
+            .map(|x| x.name.clone())
+            .unwrap_or_else(|| OTHER_CLIENT_GROUP.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_group() {
+        let groups = ClientGroups::new(&[
+            ClientGroup {
+                name: "gem_v2".to_string(),
+                user_agent: Some("^Gem/".to_string()),
+                header: Some("x-client-version".to_string()),
+                value: Some(r"^2\.".to_string()),
+            },
+            ClientGroup {
+                name: "partner".to_string(),
+                user_agent: None,
+                header: Some("x-api-key".to_string()),
+                value: None,
+            },
+        ])
+        .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::USER_AGENT, "Gem/2.1.0".parse().unwrap());
+        headers.insert("x-client-version", "2.1.0".parse().unwrap());
+        assert_eq!(groups.get_group(&headers), "gem_v2");
+
+        headers.insert("x-client-version", "1.9.0".parse().unwrap());
+        assert_eq!(groups.get_group(&headers), "other");
+
+        headers.insert("x-api-key", "secret".parse().unwrap());
+        assert_eq!(groups.get_group(&headers), "partner");
+
+        assert_eq!(ClientGroups::default().get_group(&headers), "");
+    }
+}
@@ -0,0 +1,181 @@
+use std::io::{self, Read, Write};
+
+use bytes::Bytes;
+use flate2::read::{MultiGzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+use crate::config::ContentEncoding;
+
+// Upper bound on inflated upstream bodies, guards against compression bombs
+const MAX_DECODED_BYTES: u64 = 64 * 1024 * 1024;
+
+const BROTLI_BUFFER_SIZE: usize = 4096;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+const ZSTD_LEVEL: i32 = 3;
+
+// Larger bodies are coded on the blocking pool so they don't stall other connections
+const BLOCKING_MIN_BYTES: usize = 64 * 1024;
+
+pub fn parse_content_encoding(value: &str) -> Option<ContentEncoding> {
+    match value.trim().to_lowercase().as_str() {
+        "zstd" => Some(ContentEncoding::Zstd),
+        "br" => Some(ContentEncoding::Br),
+        "gzip" | "x-gzip" => Some(ContentEncoding::Gzip),
+        "deflate" => Some(ContentEncoding::Deflate),
+        _ => None,
+    }
+}
+
+pub fn decode(encoding: ContentEncoding, body: &[u8]) -> io::Result<Vec<u8>> {
+    let reader: Box<dyn Read + '_> = match encoding {
+        ContentEncoding::Zstd => Box::new(zstd::stream::read::Decoder::new(body)?),
+        ContentEncoding::Br => Box::new(brotli::Decompressor::new(body, BROTLI_BUFFER_SIZE)),
+        ContentEncoding::Gzip => Box::new(MultiGzDecoder::new(body)),
+        ContentEncoding::Deflate => Box::new(ZlibDecoder::new(body)),
+    };
+    let mut decoded = vec![];
+    reader
+        .take(MAX_DECODED_BYTES + 1)
+        .read_to_end(&mut decoded)?;
+    if decoded.len() as u64 > MAX_DECODED_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "decoded body exceeds limit",
+        ));
+    }
+    Ok(decoded)
+}
+
+pub async fn spawn_decode(encoding: ContentEncoding, body: Bytes) -> io::Result<Vec<u8>> {
+    if body.len() < BLOCKING_MIN_BYTES {
+        return decode(encoding, &body);
+    }
+    tokio::task::spawn_blocking(move || decode(encoding, &body))
+        .await
+        .map_err(io::Error::other)?
+}
+
+pub async fn spawn_encode(encoding: ContentEncoding, body: Bytes) -> io::Result<Vec<u8>> {
+    if body.len() < BLOCKING_MIN_BYTES {
+        return encode(encoding, &body);
+    }
+    tokio::task::spawn_blocking(move || encode(encoding, &body))
+        .await
+        .map_err(io::Error::other)?
+}
+
+pub fn encode(encoding: ContentEncoding, body: &[u8]) -> io::Result<Vec<u8>> {
+    match encoding {
+        ContentEncoding::Zstd => zstd::stream::encode_all(body, ZSTD_LEVEL),
+        ContentEncoding::Br => {
+            let mut writer = brotli::CompressorWriter::new(
+                vec![],
+                BROTLI_BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            );
+            writer.write_all(body)?;
+            writer.flush()?;
+            Ok(writer.into_inner())
+        }
+        ContentEncoding::Gzip => {
+            let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        ContentEncoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(vec![], flate2::Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+    }
+}
+
+// Values of Accept or Accept-Encoding style headers with their q weights
+pub fn parse_quality_list(value: &str) -> Vec<(String, f64)> {
+    value
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let name = parts.next()?.trim().to_lowercase();
+            let quality = parts
+                .filter_map(|x| x.trim().strip_prefix("q="))
+                .find_map(|x| x.parse::<f64>().ok())
+                .unwrap_or(1.0);
+            (!name.is_empty()).then_some((name, quality))
+        })
+        .collect()
+}
+
+// Highest client q value among supported encodings, ties go to the earlier supported one
+pub fn negotiate(accept_encoding: &str, supported: &[ContentEncoding]) -> Option<ContentEncoding> {
+    let accepted = parse_quality_list(accept_encoding);
+    let wildcard = accepted.iter().find(|x| x.0 == "*").map(|x| x.1);
+    let mut best: Option<(ContentEncoding, f64)> = None;
+    for encoding in supported {
+        let quality = accepted
+            .iter()
+            .find(|x| x.0 == encoding.as_str())
+            .map(|x| x.1)
+            .or(wildcard)
+            .unwrap_or(0.0);
+        if quality > 0.0 && best.map(|x| quality > x.1).unwrap_or(true) {
+            best = Some((*encoding, quality));
+        }
+    }
+    best.map(|x| x.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let body = br#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#.repeat(100);
+        for encoding in [
+            ContentEncoding::Zstd,
+            ContentEncoding::Br,
+            ContentEncoding::Gzip,
+            ContentEncoding::Deflate,
+        ] {
+            let encoded = encode(encoding, &body).unwrap();
+            assert!(encoded.len() < body.len());
+            assert_eq!(decode(encoding, &encoded).unwrap(), body);
+        }
+        assert!(decode(ContentEncoding::Gzip, b"not gzip").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_spawn_encode_decode() {
+        let body = Bytes::from(br#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#.repeat(4096));
+        let encoded = spawn_encode(ContentEncoding::Zstd, body.clone())
+            .await
+            .unwrap();
+        let decoded = spawn_decode(ContentEncoding::Zstd, Bytes::from(encoded))
+            .await
+            .unwrap();
+        assert_eq!(decoded, body);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let supported = [
+            ContentEncoding::Zstd,
+            ContentEncoding::Br,
+            ContentEncoding::Gzip,
+        ];
+        assert_eq!(
+            negotiate("gzip, deflate, br", &supported),
+            Some(ContentEncoding::Br)
+        );
+        assert_eq!(
+            negotiate("gzip;q=1.0, br;q=0.5", &supported),
+            Some(ContentEncoding::Gzip)
+        );
+        assert_eq!(negotiate("*", &supported), Some(ContentEncoding::Zstd));
+        assert_eq!(negotiate("br;q=0, identity", &supported), None);
+        assert_eq!(negotiate("", &supported), None);
+    }
+}
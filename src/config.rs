@@ -1,20 +1,65 @@
-use std::{collections::HashMap, env};
+use std::{collections::HashMap, env, str::FromStr, time::Instant};
 
 use config::{Config, ConfigError, Environment, File};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::Method;
+use prometheus_client::metrics::histogram::exponential_buckets;
 use serde::Deserialize;
 
-use primitives::{ChainType};
-use crate::{node_service::NodeResult, proxy_request_service::NodeDomain};
+use primitives::ChainType;
+use crate::chain_service::probe::{
+    ChainIdentity, ChainIdentityMethod, HealthCheck, Probe, ProbePreset,
+};
+use crate::client_group::ClientGroups;
+use crate::header_policy;
+use crate::json_rpc;
+use crate::node_service::NodeResult;
+use crate::user_agent::UserAgentClassifier;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct NodeConfig {
     pub port: u16,
     pub address: String,
+    pub shutdown_timeout_seconds: Option<u64>,
+    pub shutdown_drain_seconds: Option<u64>,
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
     pub metrics: Metrics,
     pub domains: Vec<Domain>,
 }
 
 impl NodeConfig {
+    pub fn get_shutdown_timeout_seconds(&self) -> u64 {
+        self.shutdown_timeout_seconds.unwrap_or(30)
+    }
+
+    // Time for load balancers to observe the failing readiness probe before accept stops
+    pub fn get_shutdown_drain_seconds(&self) -> u64 {
+        self.shutdown_drain_seconds.unwrap_or(5)
+    }
+
+    pub fn path_templates_map(&self) -> HashMap<String, Vec<String>> {
+        self.domains
+            .iter()
+            .filter_map(|x| Some((x.domain.clone(), x.path_templates.clone()?)))
+            .collect()
+    }
+
+    pub fn latency_buckets_map(
+        &self,
+    ) -> Result<HashMap<String, Vec<f64>>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut map = HashMap::new();
+        for domain in &self.domains {
+            if let Some(buckets) = &domain.latency_buckets {
+                let buckets = buckets
+                    .get_buckets()
+                    .map_err(|err| format!("{}: {}", domain.domain, err))?;
+                map.insert(domain.domain.clone(), buckets);
+            }
+        }
+        Ok(map)
+    }
+
     pub fn domains_map(&self) -> HashMap<String, Domain> {
         let mut map: HashMap<String, Domain> = HashMap::new();
         for domain in &self.domains {
@@ -30,39 +75,328 @@ pub struct Metrics {
     pub address: String,
     #[serde(default)]
     pub user_agent_patterns: UserAgentPatterns,
+    #[serde(default)]
+    pub method_labels: MethodLabels,
+    pub max_series: Option<usize>,
+    #[serde(default)]
+    pub client_groups: Vec<ClientGroup>,
+    pub latency_buckets: Option<LatencyBuckets>,
+    pub latency_window: Option<LatencyWindow>,
+    pub push: Option<MetricsPush>,
+    pub auth: Option<MetricsAuth>,
+    pub compression_min_bytes: Option<usize>,
+}
+
+impl Metrics {
+    pub fn get_compression_min_bytes(&self) -> usize {
+        self.compression_min_bytes.unwrap_or(1024)
+    }
+}
+
+// Basic credentials and/or a bearer token required on the metrics listener
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct MetricsAuth {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub bearer_token: Option<String>,
+}
+
+// Push exporters reading the same registry as the scrape endpoint
+#[derive(Debug, Deserialize, Clone)]
+pub struct MetricsPush {
+    pub flush_interval_seconds: Option<u64>,
+    pub statsd: Option<StatsdConfig>,
+    pub otlp: Option<OtlpConfig>,
+}
+
+impl MetricsPush {
+    pub fn get_flush_interval_seconds(&self) -> u64 {
+        self.flush_interval_seconds.unwrap_or(10)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StatsdFormat {
+    #[default]
+    Statsd,
+    Dogstatsd,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct StatsdConfig {
+    pub address: String,
+    #[serde(default)]
+    pub format: StatsdFormat,
+    pub max_packet_size: Option<usize>,
+}
+
+impl StatsdConfig {
+    pub fn get_max_packet_size(&self) -> usize {
+        self.max_packet_size.unwrap_or(1432)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OtlpConfig {
+    pub endpoint: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub service_name: Option<String>,
+    pub timeout_seconds: Option<u64>,
+}
+
+impl OtlpConfig {
+    pub fn get_service_name(&self) -> String {
+        self.service_name.clone().unwrap_or("dynode".to_string())
+    }
+
+    pub fn get_timeout_seconds(&self) -> u64 {
+        self.timeout_seconds.unwrap_or(5)
+    }
+}
+
+// Explicit bucket bounds in milliseconds, or an exponential series
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct LatencyBuckets {
+    pub buckets: Option<Vec<f64>>,
+    pub start: Option<f64>,
+    pub factor: Option<f64>,
+    pub count: Option<u16>,
+}
+
+impl LatencyBuckets {
+    pub fn get_buckets(&self) -> Result<Vec<f64>, String> {
+        let buckets: Vec<f64> = match &self.buckets {
+            Some(buckets) => {
+                let mut buckets = buckets.clone();
+                buckets.sort_by(|a, b| a.total_cmp(b));
+                buckets.dedup();
+                buckets
+            }
+            None => {
+                let start = self.start.unwrap_or(50.0);
+                let factor = self.factor.unwrap_or(1.44);
+                if start <= 0.0 || factor <= 1.0 {
+                    return Err(format!(
+                        "latency buckets need start > 0 and factor > 1, got {} and {}",
+                        start, factor
+                    ));
+                }
+                exponential_buckets(start, factor, self.count.unwrap_or(12)).collect()
+            }
+        };
+        if buckets.is_empty() || buckets.iter().any(|x| !x.is_finite()) {
+            return Err("latency buckets must be a non empty list of finite values".to_string());
+        }
+        Ok(buckets)
+    }
+}
+
+// Recent latency histograms, aggregatable across instances unlike precomputed quantiles
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct LatencyWindow {
+    pub max_age_seconds: Option<u64>,
+}
+
+impl LatencyWindow {
+    pub fn get_max_age_seconds(&self) -> u64 {
+        self.max_age_seconds.unwrap_or(60).max(1)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ClientGroup {
+    pub name: String,
+    pub user_agent: Option<String>,
+    pub header: Option<String>,
+    pub value: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct MetricsConfig {
-    pub user_agent_patterns: UserAgentPatterns,
+    pub user_agents: UserAgentClassifier,
+    pub client_groups: ClientGroups,
+    pub method_labels: MethodLabels,
+    pub path_templates: HashMap<String, Vec<String>>,
+    pub max_series: Option<usize>,
+    pub latency_buckets: Option<Vec<f64>>,
+    pub domain_latency_buckets: HashMap<String, Vec<f64>>,
+    pub latency_window: LatencyWindow,
+}
+
+impl MetricsConfig {
+    pub fn get_max_series(&self) -> usize {
+        self.max_series.unwrap_or(10_000)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct MethodLabels {
+    pub allowed: Option<Vec<String>>,
+    pub max_methods: Option<usize>,
+}
+
+impl MethodLabels {
+    pub fn get_max_methods(&self) -> usize {
+        self.max_methods.unwrap_or(100)
+    }
 }
 
 #[derive(Debug, Deserialize, Clone, Default)]
 pub struct UserAgentPatterns {
     #[serde(default)]
     pub patterns: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub rules: Vec<UserAgentRule>,
+    pub version: Option<UserAgentVersion>,
+}
+
+impl UserAgentPatterns {
+    // Ordered rules first, then the legacy patterns map sorted by category
+    pub fn get_rules(&self) -> Vec<(String, Vec<String>)> {
+        let mut patterns: Vec<(String, Vec<String>)> = self.patterns.clone().into_iter().collect();
+        patterns.sort_by(|a, b| a.0.cmp(&b.0));
+        self.rules
+            .iter()
+            .map(|x| (x.name.clone(), x.patterns.clone()))
+            .chain(patterns)
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct UserAgentRule {
+    pub name: String,
+    pub patterns: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct UserAgentVersion {
+    pub pattern: String,
+    pub max_versions: Option<usize>,
+}
+
+impl UserAgentVersion {
+    pub fn get_max_versions(&self) -> usize {
+        self.max_versions.unwrap_or(20)
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Domain {
     pub domain: String,
     pub chain_type: String,
+    pub json_rpc: Option<bool>,
+    pub probe: Option<Probe>,
+    pub health_checks: Option<Vec<HealthCheck>>,
+    pub max_block_age_seconds: Option<u64>,
+    pub chain_identity: Option<ChainIdentity>,
+    pub consensus: Option<Consensus>,
+    pub hedge: Option<Hedge>,
+    pub broadcast: Option<Broadcast>,
+    pub sticky: Option<Sticky>,
+    pub error_classification: Option<ErrorClassification>,
+    pub path_templates: Option<Vec<String>>,
+    pub latency_buckets: Option<LatencyBuckets>,
+    pub header_policy: Option<HeaderPolicy>,
+    pub compression: Option<Compression>,
+    pub max_request_body_bytes: Option<usize>,
     pub block_delay: Option<u64>,
     pub poll_interval_seconds: Option<u64>,
     pub urls: Vec<Url>,
 }
 
 impl Domain {
+    fn resolve_header_templates(&mut self) -> Result<(), String> {
+        if let Some(policy) = &mut self.header_policy {
+            policy.resolve_templates()?;
+        }
+        for url in &mut self.urls {
+            url.resolve_header_templates()?;
+        }
+        Ok(())
+    }
+
+    // Settings that would silently never apply: identity without a probe never verifies,
+    // hedge percentiles outside 0..1 never resolve to a delay
+    fn validate(&self) -> Result<(), String> {
+        if self.chain_identity.is_some() && self.get_probe().is_none() {
+            return Err("chain_identity requires a probe".to_string());
+        }
+        if self.chain_identity.is_some() && self.get_chain_identity().is_none() {
+            return Err("chain_identity requires a method for this probe".to_string());
+        }
+        if let Some(percentile) = self.hedge.as_ref().and_then(|x| x.percentile) {
+            if !(percentile > 0.0 && percentile < 1.0) {
+                return Err(format!(
+                    "hedge percentile {} must be a fraction between 0 and 1, like 0.95",
+                    percentile
+                ));
+            }
+        }
+        Ok(())
+    }
+
     pub fn get_poll_interval_seconds(&self) -> u64 {
         self.poll_interval_seconds.unwrap_or(600) // 10 minutes
     }
 
+    pub fn get_broadcast(&self, http_method: &Method, methods: &[String]) -> Option<&Broadcast> {
+        self.broadcast
+            .as_ref()
+            .filter(|x| x.is_enabled(&self.chain_type, http_method, methods))
+    }
+
+    pub fn get_compression(&self) -> Compression {
+        self.compression.clone().unwrap_or_default()
+    }
+
+    pub fn get_max_request_body_bytes(&self) -> usize {
+        self.max_request_body_bytes.unwrap_or(10 * 1024 * 1024)
+    }
+
     pub fn get_block_delay(&self) -> u64 {
         self.block_delay.unwrap_or(100)
     }
 
-    pub fn get_node_domain(&self, url: Url) -> NodeDomain {
-        NodeDomain { url }
+    pub fn get_probe(&self) -> Option<Probe> {
+        self.probe.clone().or_else(|| {
+            ProbePreset::from_chain_type(&self.chain_type).map(|name| Probe::Preset { name })
+        })
+    }
+
+    pub fn get_health_checks(&self) -> Vec<HealthCheck> {
+        self.health_checks.clone().unwrap_or_default()
+    }
+
+    pub fn get_chain_identity(&self) -> Option<(ChainIdentityMethod, String)> {
+        let identity = self.chain_identity.clone()?;
+        let method = identity.method.or_else(|| match self.get_probe()? {
+            Probe::Preset { name } => ChainIdentityMethod::from_preset(name),
+            _ => None,
+        })?;
+        Some((method, identity.expected))
+    }
+
+    pub fn is_head_stale(&self, updated_at: Option<Instant>) -> bool {
+        match (self.max_block_age_seconds, updated_at) {
+            (Some(max_age), Some(updated_at)) => updated_at.elapsed().as_secs() > max_age,
+            _ => false,
+        }
+    }
+
+    pub fn is_json_rpc(&self) -> bool {
+        self.json_rpc.unwrap_or_else(|| {
+            matches!(
+                ChainType::from_str(&self.chain_type),
+                Ok(ChainType::Ethereum
+                    | ChainType::Solana
+                    | ChainType::Sui
+                    | ChainType::Xrp
+                    | ChainType::Near)
+            )
+        })
     }
 
     pub fn is_url_behind(&self, url: Url, results: Vec<NodeResult>) -> bool {
@@ -84,11 +418,371 @@ impl Domain {
     }
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct Consensus {
+    pub methods: Vec<String>,
+    pub upstreams: usize,
+    pub quorum: Option<usize>,
+}
+
+impl Consensus {
+    pub fn get_quorum(&self) -> usize {
+        self.quorum.unwrap_or(self.upstreams / 2 + 1)
+    }
+
+    pub fn is_enabled(&self, methods: &[String]) -> bool {
+        is_method_match(&self.methods, methods)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Hedge {
+    pub methods: Option<Vec<String>>,
+    pub delay_ms: Option<u64>,
+    // Fraction such as 0.95, of recent upstream latency for the host across all methods
+    pub percentile: Option<f64>,
+}
+
+impl Hedge {
+    pub fn get_delay_ms(&self) -> u64 {
+        self.delay_ms.unwrap_or(500)
+    }
+
+    // Opt in only, hedging duplicates load on every upstream it touches
+    pub fn is_enabled(&self, http_method: &Method, methods: &[String]) -> bool {
+        let Some(patterns) = &self.methods else {
+            return false;
+        };
+        is_method_match(patterns, methods)
+            && methods
+                .iter()
+                .all(|x| json_rpc::is_idempotent(http_method, x))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StickyKey {
+    ClientIp,
+    ApiKey,
+    Header,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Sticky {
+    pub key: StickyKey,
+    pub header: Option<String>,
+    pub ttl_seconds: Option<u64>,
+}
+
+impl Sticky {
+    pub fn get_ttl_seconds(&self) -> u64 {
+        self.ttl_seconds.unwrap_or(300)
+    }
+
+    pub fn get_header(&self) -> String {
+        self.header.clone().unwrap_or_else(|| match self.key {
+            StickyKey::ApiKey => "x-api-key".to_string(),
+            _ => "x-session-id".to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ErrorRule {
+    pub name: String,
+    pub code: Option<i64>,
+    pub message: Option<String>,
+    pub retry: Option<bool>,
+}
+
+impl ErrorRule {
+    pub fn is_retry(&self) -> bool {
+        self.retry.unwrap_or(false)
+    }
+
+    fn matches(&self, error: &json_rpc::ErrorObject) -> bool {
+        let code = self.code.map(|x| error.code == Some(x));
+        let message = self
+            .message
+            .as_ref()
+            .map(|x| error.message.to_lowercase().contains(&x.to_lowercase()));
+        match (code, message) {
+            (None, None) => false,
+            (code, message) => code.unwrap_or(true) && message.unwrap_or(true),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ErrorClassification {
+    pub rules: Vec<ErrorRule>,
+    pub eject_threshold: Option<u64>,
+    pub max_retries: Option<usize>,
+}
+
+impl ErrorClassification {
+    pub fn get_eject_threshold(&self) -> u64 {
+        self.eject_threshold.unwrap_or(10)
+    }
+
+    pub fn get_max_retries(&self) -> usize {
+        self.max_retries.unwrap_or(1)
+    }
+
+    pub fn classify(&self, body: &[u8]) -> Option<ErrorRule> {
+        json_rpc::get_errors(body)
+            .iter()
+            .find_map(|error| self.rules.iter().find(|rule| rule.matches(error)).cloned())
+    }
+}
+
+const DEFAULT_RETAINED_BLOCKS: u64 = 128;
+
+const ALREADY_KNOWN_ERRORS: &[&str] = &[
+    "already known",
+    "known transaction",
+    "already imported",
+    "already in mempool",
+    "already been processed",
+    "AlreadyProcessed",
+    "DUP_TRANSACTION",
+    "txn-already-known",
+    "txn-already-in-mempool",
+    "tx already exists in cache",
+];
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Broadcast {
+    pub methods: Option<Vec<String>>,
+    pub upstreams: Option<usize>,
+    pub already_known: Option<Vec<String>>,
+    pub accepted: Option<BroadcastAccepted>,
+}
+
+// Response field that must equal value for a submission to count as accepted
 #[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct BroadcastAccepted {
+    pub pointer: String,
+    pub value: serde_json::Value,
+}
+
+impl Broadcast {
+    pub fn get_upstreams(&self) -> usize {
+        self.upstreams.unwrap_or(usize::MAX)
+    }
+
+    // Without a method list only the chain's transaction submission is broadcast
+    pub fn is_enabled(&self, chain_type: &str, http_method: &Method, methods: &[String]) -> bool {
+        match &self.methods {
+            Some(patterns) => is_method_match(patterns, methods),
+            None => {
+                let patterns: Vec<String> = get_submit_methods(chain_type)
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect();
+                is_method_match(&patterns, methods)
+                    && methods
+                        .iter()
+                        .all(|x| !json_rpc::is_idempotent(http_method, x))
+            }
+        }
+    }
+
+    // Some REST APIs report rejected submissions with a 200, e.g. Tron's {"result": false}
+    pub fn is_accepted(&self, chain_type: &str, body: &[u8]) -> bool {
+        let accepted = self
+            .accepted
+            .clone()
+            .or_else(|| match ChainType::from_str(chain_type) {
+                Ok(ChainType::Tron) => Some(BroadcastAccepted {
+                    pointer: "/result".to_string(),
+                    value: serde_json::Value::Bool(true),
+                }),
+                _ => None,
+            });
+        match accepted {
+            Some(accepted) => serde_json::from_slice::<serde_json::Value>(body)
+                .ok()
+                .and_then(|x| x.pointer(&accepted.pointer).cloned())
+                .is_some_and(|x| x == accepted.value),
+            None => !json_rpc::has_error(body),
+        }
+    }
+
+    pub fn is_already_known(&self, body: &[u8]) -> bool {
+        let body = String::from_utf8_lossy(body).to_lowercase();
+        match &self.already_known {
+            Some(patterns) => patterns.iter().any(|x| body.contains(&x.to_lowercase())),
+            None => ALREADY_KNOWN_ERRORS
+                .iter()
+                .any(|x| body.contains(&x.to_lowercase())),
+        }
+    }
+}
+
+fn get_submit_methods(chain_type: &str) -> &'static [&'static str] {
+    match ChainType::from_str(chain_type) {
+        Ok(ChainType::Ethereum) => &["eth_sendRawTransaction"],
+        Ok(ChainType::Bitcoin) => &["/api/v2/sendtx"],
+        Ok(ChainType::Solana) => &["sendTransaction"],
+        Ok(ChainType::Cosmos) => &["/cosmos/tx/v1beta1/txs"],
+        Ok(ChainType::Ton) => &["/api/v2/sendBoc"],
+        Ok(ChainType::Tron) => &["/wallet/broadcasttransaction", "/wallet/broadcasthex"],
+        Ok(ChainType::Aptos) => &["/v1/transactions"],
+        Ok(ChainType::Sui) => &["sui_executeTransactionBlock"],
+        Ok(ChainType::Xrp) => &["submit"],
+        Ok(ChainType::Near) => &["broadcast_tx_async", "broadcast_tx_commit", "send_tx"],
+        _ => &[],
+    }
+}
+
+// Every method must match; entries starting with "/" match REST path prefixes
+pub fn is_method_match(patterns: &[String], methods: &[String]) -> bool {
+    !methods.is_empty()
+        && methods.iter().all(|method| {
+            patterns.iter().any(|pattern| {
+                pattern == method
+                    || (pattern.starts_with('/') && method.starts_with(pattern.as_str()))
+            })
+        })
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentEncoding {
+    Zstd,
+    Br,
+    Gzip,
+    Deflate,
+}
+
+impl ContentEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+            Self::Br => "br",
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+}
+
+// Opt in per domain, upstream responses are decoded once then encoded again for the client
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct Compression {
+    pub upstream: Option<bool>,
+    pub client: Option<bool>,
+    pub min_bytes: Option<usize>,
+    pub encodings: Option<Vec<ContentEncoding>>,
+}
+
+impl Compression {
+    pub fn is_upstream_enabled(&self) -> bool {
+        self.upstream.unwrap_or(false)
+    }
+
+    pub fn is_client_enabled(&self) -> bool {
+        self.client.unwrap_or(false)
+    }
+
+    pub fn get_min_bytes(&self) -> usize {
+        self.min_bytes.unwrap_or(1024)
+    }
+
+    // Preference order, used for Accept-Encoding upstream and to break ties with clients
+    pub fn get_encodings(&self) -> Vec<ContentEncoding> {
+        self.encodings.clone().unwrap_or(vec![
+            ContentEncoding::Zstd,
+            ContentEncoding::Br,
+            ContentEncoding::Gzip,
+        ])
+    }
+}
+
+// Header rules for each direction, Url rules are applied on top of the Domain rules
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+pub struct HeaderPolicy {
+    pub request: Option<HeaderRules>,
+    pub response: Option<HeaderRules>,
+}
+
+impl HeaderPolicy {
+    fn resolve_templates(&mut self) -> Result<(), String> {
+        for rules in [&mut self.request, &mut self.response]
+            .into_iter()
+            .flatten()
+        {
+            rules.resolve_templates()?;
+        }
+        Ok(())
+    }
+}
+
+// forward and strip accept names or prefixes ending with "*", set values may use ${ENV_VAR}
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+pub struct HeaderRules {
+    #[serde(default)]
+    pub forward: Vec<String>,
+    #[serde(default)]
+    pub strip: Vec<String>,
+    #[serde(default)]
+    pub set: HashMap<String, String>,
+}
+
+impl HeaderRules {
+    fn resolve_templates(&mut self) -> Result<(), String> {
+        for (name, value) in self.set.iter_mut() {
+            *value = header_policy::render_template(value)?;
+            HeaderName::from_str(name).map_err(|_| format!("invalid header name {}", name))?;
+            HeaderValue::from_str(value)
+                .map_err(|_| format!("invalid value for header {}", name))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
 pub struct Url {
     pub url: String,
     pub headers: Option<HashMap<String, String>>,
     pub urls_override: Option<HashMap<String, Url>>,
+    pub archive: Option<bool>,
+    pub retained_blocks: Option<u64>,
+    pub header_policy: Option<HeaderPolicy>,
+    pub forwarded_headers: Option<Vec<ForwardedHeader>>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardedHeader {
+    XForwardedFor,
+    XForwardedProto,
+    XRealIp,
+    Forwarded,
+}
+
+impl Url {
+    fn resolve_header_templates(&mut self) -> Result<(), String> {
+        if let Some(policy) = &mut self.header_policy {
+            policy.resolve_templates()?;
+        }
+        for url in self.urls_override.iter_mut().flat_map(|x| x.values_mut()) {
+            url.resolve_header_templates()?;
+        }
+        Ok(())
+    }
+
+    // Urls without a declared capability are assumed to serve any height
+    pub fn can_serve_block(&self, block_number: u64, latest_block: u64) -> bool {
+        let age = latest_block.saturating_sub(block_number);
+        match (self.archive, self.retained_blocks) {
+            (Some(true), _) => true,
+            (_, Some(retained_blocks)) => age <= retained_blocks,
+            (Some(false), None) => age <= DEFAULT_RETAINED_BLOCKS,
+            (None, None) => true,
+        }
+    }
 }
 
 impl NodeConfig {
@@ -103,6 +797,110 @@ impl NodeConfig {
                     .separator("_"),
             )
             .build()?;
-        s.try_deserialize()
+        let mut config: NodeConfig = s.try_deserialize()?;
+        for domain in &mut config.domains {
+            domain
+                .resolve_header_templates()
+                .and_then(|_| domain.validate())
+                .map_err(|err| ConfigError::Message(format!("{}: {}", domain.domain, err)))?;
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression_is_opt_in() {
+        let compression = Compression::default();
+        assert!(!compression.is_upstream_enabled());
+        assert!(!compression.is_client_enabled());
+
+        let compression = Compression {
+            upstream: Some(true),
+            client: Some(true),
+            ..Default::default()
+        };
+        assert!(compression.is_upstream_enabled());
+        assert!(compression.is_client_enabled());
+    }
+
+    #[test]
+    fn test_validate_hedge_percentile() {
+        let domain = |percentile: f64| -> Domain {
+            serde_json::from_value(serde_json::json!({
+                "domain": "localhost",
+                "chain_type": "ethereum",
+                "hedge": {"methods": ["eth_call"], "percentile": percentile},
+                "urls": [{"url": "https://a.com"}],
+            }))
+            .unwrap()
+        };
+        assert!(domain(0.95).validate().is_ok());
+        assert!(domain(95.0).validate().is_err());
+        assert!(domain(1.0).validate().is_err());
+        assert!(domain(0.0).validate().is_err());
+    }
+
+    #[test]
+    fn test_broadcast_is_enabled() {
+        let broadcast = Broadcast {
+            methods: None,
+            upstreams: Some(3),
+            already_known: None,
+            accepted: None,
+        };
+        let methods = |x: &str| vec![x.to_string()];
+        assert!(broadcast.is_enabled(
+            "ethereum",
+            &Method::POST,
+            &methods("eth_sendRawTransaction")
+        ));
+        assert!(!broadcast.is_enabled("ethereum", &Method::POST, &methods("eth_sendBundle")));
+        assert!(!broadcast.is_enabled("ethereum", &Method::POST, &methods("eth_call")));
+        assert!(broadcast.is_enabled("cosmos", &Method::POST, &methods("/cosmos/tx/v1beta1/txs")));
+        assert!(!broadcast.is_enabled(
+            "cosmos",
+            &Method::GET,
+            &methods("/cosmos/tx/v1beta1/txs/ABCD")
+        ));
+
+        let broadcast = Broadcast {
+            methods: Some(methods("eth_sendBundle")),
+            ..broadcast
+        };
+        assert!(broadcast.is_enabled("ethereum", &Method::POST, &methods("eth_sendBundle")));
+    }
+
+    #[test]
+    fn test_broadcast_is_accepted() {
+        let broadcast = Broadcast {
+            methods: None,
+            upstreams: None,
+            already_known: None,
+            accepted: None,
+        };
+        assert!(broadcast.is_accepted("tron", br#"{"result":true,"txid":"ab"}"#));
+        assert!(!broadcast.is_accepted(
+            "tron",
+            br#"{"result":false,"code":"SIGERROR","message":"76616c6964"}"#
+        ));
+        assert!(broadcast.is_accepted("ethereum", br#"{"id":1,"result":"0xab"}"#));
+        assert!(!broadcast.is_accepted(
+            "ethereum",
+            br#"{"id":1,"error":{"code":-32000,"message":"nonce too low"}}"#
+        ));
+
+        let broadcast = Broadcast {
+            accepted: Some(BroadcastAccepted {
+                pointer: "/status".to_string(),
+                value: serde_json::json!("ok"),
+            }),
+            ..broadcast
+        };
+        assert!(broadcast.is_accepted("ethereum", br#"{"status":"ok"}"#));
+        assert!(!broadcast.is_accepted("ethereum", br#"{"status":"error"}"#));
     }
 }
@@ -0,0 +1,205 @@
+use std::env;
+use std::str::FromStr;
+
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+
+use crate::config::HeaderRules;
+
+// Forwarded unless a policy strips them
+pub const REQUEST_HEADERS: &[&str] = &["content-type", "content-encoding", "accept"];
+pub const RESPONSE_HEADERS: &[&str] = &[
+    "content-type",
+    "content-encoding",
+    "cache-control",
+    "retry-after",
+    "x-ratelimit-*",
+    "ratelimit-*",
+];
+
+// Credentials and spoofable client addresses are only forwarded when named explicitly
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-real-ip",
+    "forwarded",
+];
+
+// Connection specific, set by hyper for every hop
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "te",
+    "trailer",
+    "upgrade",
+    "host",
+    "content-length",
+];
+
+fn is_match(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+pub fn filter_headers(headers: &HeaderMap, defaults: &[&str], rules: &[&HeaderRules]) -> HeaderMap {
+    let forward: Vec<String> = defaults
+        .iter()
+        .map(|x| x.to_string())
+        .chain(
+            rules
+                .iter()
+                .flat_map(|x| &x.forward)
+                .map(|x| x.to_lowercase()),
+        )
+        .collect();
+    let strip: Vec<String> = rules
+        .iter()
+        .flat_map(|x| &x.strip)
+        .map(|x| x.to_lowercase())
+        .collect();
+
+    let mut result = HeaderMap::new();
+    for (name, value) in headers {
+        let name_str = name.as_str();
+        if HOP_BY_HOP_HEADERS.contains(&name_str) {
+            continue;
+        }
+        let is_forwarded = match SENSITIVE_HEADERS.contains(&name_str) {
+            true => forward.iter().any(|x| x == name_str),
+            false => forward.iter().any(|x| is_match(x, name_str)),
+        };
+        if is_forwarded && !strip.iter().any(|x| is_match(x, name_str)) {
+            result.append(name.clone(), value.clone());
+        }
+    }
+    result
+}
+
+// Later rules override earlier ones, values are validated when the config is loaded
+pub fn set_headers(headers: &mut HeaderMap, rules: &[&HeaderRules]) {
+    for (name, value) in rules.iter().flat_map(|x| &x.set) {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_str(name), HeaderValue::from_str(value)) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+// Replaces ${NAME} with the environment variable, missing variables are an error
+pub fn render_template(value: &str) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..]
+            .find('}')
+            .ok_or(format!("unterminated template in {}", value))?;
+        let name = &rest[start + 2..start + end];
+        let variable =
+            env::var(name).map_err(|_| format!("environment variable {} not set", name))?;
+        result.push_str(&rest[..start]);
+        result.push_str(&variable);
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::headers;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_filter_headers() {
+        let request = headers(&[
+            ("content-type", "application/json"),
+            ("authorization", "Bearer secret"),
+            ("x-client-id", "1"),
+            ("x-client-debug", "1"),
+            ("connection", "keep-alive"),
+        ]);
+
+        let filtered = filter_headers(&request, REQUEST_HEADERS, &[]);
+        assert_eq!(filtered, headers(&[("content-type", "application/json")]));
+
+        let rules = HeaderRules {
+            forward: vec!["*".to_string()],
+            strip: vec!["x-client-debug".to_string()],
+            ..Default::default()
+        };
+        let filtered = filter_headers(&request, REQUEST_HEADERS, &[&rules]);
+        assert_eq!(
+            filtered,
+            headers(&[("content-type", "application/json"), ("x-client-id", "1")])
+        );
+
+        let rules = HeaderRules {
+            forward: vec!["Authorization".to_string()],
+            ..Default::default()
+        };
+        let filtered = filter_headers(&request, REQUEST_HEADERS, &[&rules]);
+        assert!(filtered.contains_key("authorization"));
+    }
+
+    #[test]
+    fn test_response_headers() {
+        let response = headers(&[
+            ("content-type", "application/json"),
+            ("x-ratelimit-remaining", "10"),
+            ("retry-after", "1"),
+            ("set-cookie", "session=1"),
+            ("server", "nginx"),
+        ]);
+        let filtered = filter_headers(&response, RESPONSE_HEADERS, &[]);
+        assert_eq!(
+            filtered,
+            headers(&[
+                ("content-type", "application/json"),
+                ("x-ratelimit-remaining", "10"),
+                ("retry-after", "1"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_set_headers() {
+        let domain = HeaderRules {
+            set: HashMap::from([
+                ("x-api-key".to_string(), "domain".to_string()),
+                ("x-source".to_string(), "dynode".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let url = HeaderRules {
+            set: HashMap::from([("x-api-key".to_string(), "url".to_string())]),
+            ..Default::default()
+        };
+        let mut result = HeaderMap::new();
+        set_headers(&mut result, &[&domain, &url]);
+        assert_eq!(
+            result,
+            headers(&[("x-api-key", "url"), ("x-source", "dynode")])
+        );
+    }
+
+    #[test]
+    fn test_render_template() {
+        env::set_var("DYNODE_TEST_API_KEY", "secret");
+        assert_eq!(
+            render_template("Bearer ${DYNODE_TEST_API_KEY}"),
+            Ok("Bearer secret".to_string())
+        );
+        assert_eq!(render_template("plain"), Ok("plain".to_string()));
+        assert!(render_template("${DYNODE_TEST_MISSING}").is_err());
+        assert!(render_template("${DYNODE_TEST_API_KEY").is_err());
+    }
+}
@@ -0,0 +1,298 @@
+use hyper::Method;
+use serde_json::Value;
+
+// Requests with side effects, never duplicated speculatively
+const NON_IDEMPOTENT_METHODS: &[&str] = &[
+    "eth_sendRawTransaction",
+    "eth_sendTransaction",
+    "eth_sendBundle",
+    "eth_sendPrivateTransaction",
+    "sendTransaction",
+    "sui_executeTransactionBlock",
+    "submit",
+    "broadcast_tx_async",
+    "broadcast_tx_sync",
+    "broadcast_tx_commit",
+    "send_tx",
+    "sendrawtransaction",
+    "author_submitExtrinsic",
+    "author_submitAndWatchExtrinsic",
+    "submitTransaction",
+];
+
+// REST submissions share path prefixes with read endpoints, so the HTTP method must match too
+const NON_IDEMPOTENT_PATHS: &[(Method, &str)] = &[
+    (Method::POST, "/wallet/broadcasttransaction"),
+    (Method::POST, "/wallet/broadcasthex"),
+    (Method::GET, "/api/v2/sendtx/"),
+    (Method::POST, "/api/v2/sendtx"),
+    (Method::POST, "/cosmos/tx/v1beta1/txs"),
+    (Method::POST, "/v1/transactions"),
+    (Method::POST, "/api/v2/sendBoc"),
+    (Method::POST, "/v2/transactions"),
+    (Method::POST, "/transactions"),
+    (Method::POST, "/tx/submit"),
+    (Method::POST, "/api/v0/tx/submit"),
+];
+
+pub fn is_idempotent(http_method: &Method, method: &str) -> bool {
+    !NON_IDEMPOTENT_METHODS.contains(&method)
+        && !NON_IDEMPOTENT_PATHS
+            .iter()
+            .any(|(x, path)| x == http_method && method.starts_with(path))
+}
+
+pub fn get_methods(body: &[u8]) -> Vec<String> {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Array(requests)) => requests.iter().filter_map(get_method).collect(),
+        Ok(request) => get_method(&request).into_iter().collect(),
+        Err(_) => vec![],
+    }
+}
+
+fn get_method(request: &Value) -> Option<String> {
+    request
+        .get("method")
+        .and_then(|x| x.as_str())
+        .map(|x| x.to_string())
+}
+
+// Ids echoed back in proxy generated error responses, batches get one error per request
+#[derive(Debug, Clone, PartialEq)]
+pub enum RequestId {
+    Single(Value),
+    Batch(Vec<Value>),
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        Self::Single(Value::Null)
+    }
+}
+
+pub fn get_request_id(body: &[u8]) -> RequestId {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Array(requests)) if !requests.is_empty() => {
+            RequestId::Batch(requests.iter().map(get_id).collect())
+        }
+        Ok(request @ Value::Object(_)) => RequestId::Single(get_id(&request)),
+        _ => RequestId::default(),
+    }
+}
+
+fn get_id(request: &Value) -> Value {
+    request.get("id").cloned().unwrap_or(Value::Null)
+}
+
+// Lowest explicit block height referenced by the request, tags like latest are ignored
+pub fn get_block_number(body: &[u8]) -> Option<u64> {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Array(requests)) => requests.iter().filter_map(get_request_block).min(),
+        Ok(request) => get_request_block(&request),
+        Err(_) => None,
+    }
+}
+
+fn get_request_block(request: &Value) -> Option<u64> {
+    let params = request.get("params")?;
+    let block = match request.get("method")?.as_str()? {
+        "eth_getBalance" | "eth_getCode" | "eth_getTransactionCount" | "eth_call" => {
+            params.get(1)?
+        }
+        "eth_getStorageAt" => params.get(2)?,
+        "eth_getBlockByNumber" | "eth_getBlockTransactionCountByNumber" => params.get(0)?,
+        "eth_getLogs" => params.get(0)?.get("fromBlock")?,
+        _ => return None,
+    };
+    parse_block_tag(block)
+}
+
+fn parse_block_tag(block: &Value) -> Option<u64> {
+    // EIP-1898 block parameter object
+    if let Some(block_number) = block.get("blockNumber") {
+        return parse_block_tag(block_number);
+    }
+    match block.as_str()? {
+        "earliest" => Some(0),
+        value => u64::from_str_radix(value.strip_prefix("0x")?, 16).ok(),
+    }
+}
+
+pub fn has_error(body: &[u8]) -> bool {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Array(responses)) => responses.iter().any(is_error),
+        Ok(response) => is_error(&response),
+        Err(_) => false,
+    }
+}
+
+fn is_error(response: &Value) -> bool {
+    response.get("error").is_some_and(|x| !x.is_null())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorObject {
+    pub code: Option<i64>,
+    pub message: String,
+}
+
+pub fn get_errors(body: &[u8]) -> Vec<ErrorObject> {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Array(responses)) => responses.iter().filter_map(get_error).collect(),
+        Ok(response) => get_error(&response).into_iter().collect(),
+        Err(_) => vec![],
+    }
+}
+
+fn get_error(response: &Value) -> Option<ErrorObject> {
+    let error = response.get("error").filter(|x| !x.is_null())?;
+    let message = match error.get("message").and_then(|x| x.as_str()) {
+        Some(message) => message.to_string(),
+        None => error.as_str().map(|x| x.to_string()).unwrap_or_default(),
+    };
+    Some(ErrorObject {
+        code: error.get("code").and_then(|x| x.as_i64()),
+        message,
+    })
+}
+
+// https://www.jsonrpc.org/specification#error_object, geth and others use their own wording
+pub fn is_method_not_found(body: &[u8]) -> bool {
+    get_errors(body).iter().any(|x| {
+        let message = x.message.to_lowercase();
+        x.code == Some(-32601)
+            || message.contains("method not found")
+            || message.contains("does not exist")
+    })
+}
+
+pub fn normalize_response(body: &[u8]) -> String {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Array(responses)) => {
+            Value::Array(responses.into_iter().map(strip_envelope).collect()).to_string()
+        }
+        Ok(response) => strip_envelope(response).to_string(),
+        Err(_) => String::from_utf8_lossy(body).to_string(),
+    }
+}
+
+fn strip_envelope(mut response: Value) -> Value {
+    if let Some(object) = response.as_object_mut() {
+        object.remove("id");
+        object.remove("jsonrpc");
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_get_methods() {
+        let body = br#"{"jsonrpc":"2.0","id":1,"method":"eth_blockNumber","params":[]}"#;
+        assert_eq!(get_methods(body), vec!["eth_blockNumber"]);
+
+        let body = br#"[{"id":1,"method":"eth_call"},{"id":2,"method":"eth_getBalance"}]"#;
+        assert_eq!(get_methods(body), vec!["eth_call", "eth_getBalance"]);
+
+        assert!(get_methods(b"not json").is_empty());
+    }
+
+    #[test]
+    fn test_get_request_id() {
+        let body = br#"{"jsonrpc":"2.0","id":"a","method":"eth_blockNumber"}"#;
+        assert_eq!(get_request_id(body), RequestId::Single(json!("a")));
+
+        let body = br#"[{"id":1,"method":"eth_call"},{"method":"eth_chainId"}]"#;
+        assert_eq!(
+            get_request_id(body),
+            RequestId::Batch(vec![json!(1), Value::Null])
+        );
+
+        assert_eq!(get_request_id(b"[]"), RequestId::default());
+        assert_eq!(get_request_id(b"not json"), RequestId::default());
+    }
+
+    #[test]
+    fn test_is_idempotent() {
+        assert!(is_idempotent(&Method::POST, "eth_call"));
+        assert!(!is_idempotent(&Method::POST, "eth_sendRawTransaction"));
+        assert!(!is_idempotent(&Method::GET, "/api/v2/sendtx/0100"));
+        assert!(!is_idempotent(&Method::POST, "/cosmos/tx/v1beta1/txs"));
+        assert!(is_idempotent(&Method::GET, "/cosmos/tx/v1beta1/txs/ABCD"));
+        assert!(!is_idempotent(&Method::POST, "/v1/transactions"));
+        assert!(is_idempotent(&Method::GET, "/v1/transactions/by_hash/0x1"));
+        assert!(!is_idempotent(&Method::POST, "author_submitExtrinsic"));
+        assert!(!is_idempotent(&Method::POST, "/v2/transactions"));
+        assert!(is_idempotent(&Method::GET, "/v2/transactions/params"));
+        assert!(!is_idempotent(&Method::POST, "/api/v0/tx/submit"));
+    }
+
+    #[test]
+    fn test_get_block_number() {
+        let body = br#"{"method":"eth_getBalance","params":["0xabc","0x10"]}"#;
+        assert_eq!(get_block_number(body), Some(16));
+
+        let body = br#"{"method":"eth_call","params":[{},"latest"]}"#;
+        assert_eq!(get_block_number(body), None);
+
+        let body = br#"[
+            {"method":"eth_getLogs","params":[{"fromBlock":"0x20","toBlock":"latest"}]},
+            {"method":"eth_call","params":[{},{"blockNumber":"0x5"}]}
+        ]"#;
+        assert_eq!(get_block_number(body), Some(5));
+    }
+
+    #[test]
+    fn test_has_error() {
+        assert!(has_error(
+            br#"{"id":1,"error":{"code":-32000,"message":"already known"}}"#
+        ));
+        assert!(!has_error(br#"{"id":1,"result":"0x1","error":null}"#));
+        assert!(!has_error(b"0x1"));
+    }
+
+    #[test]
+    fn test_get_errors() {
+        let body = br#"[
+            {"id":1,"result":"0x1"},
+            {"id":2,"error":{"code":-32005,"message":"limit exceeded"}}
+        ]"#;
+        assert_eq!(
+            get_errors(body),
+            vec![ErrorObject {
+                code: Some(-32005),
+                message: "limit exceeded".to_string(),
+            }]
+        );
+        assert!(get_errors(br#"{"id":1,"result":"0x1"}"#).is_empty());
+    }
+
+    #[test]
+    fn test_is_method_not_found() {
+        assert!(is_method_not_found(
+            br#"{"id":1,"error":{"code":-32601,"message":"Method not found"}}"#
+        ));
+        assert!(is_method_not_found(
+            br#"{"id":1,"error":{"code":-32000,"message":"the method foo does not exist/is not available"}}"#
+        ));
+        assert!(!is_method_not_found(
+            br#"{"id":1,"error":{"code":-32000,"message":"execution reverted"}}"#
+        ));
+        assert!(!is_method_not_found(br#"{"id":1,"result":"0x1"}"#));
+    }
+
+    #[test]
+    fn test_normalize_response() {
+        assert_eq!(
+            normalize_response(br#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#),
+            normalize_response(br#"{"id":7,"result":"0x1","jsonrpc":"2.0"}"#),
+        );
+        assert_ne!(
+            normalize_response(br#"{"id":1,"result":"0x1"}"#),
+            normalize_response(br#"{"id":1,"result":"0x2"}"#),
+        );
+    }
+}
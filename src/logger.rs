@@ -1,30 +1,31 @@
-use hyper::{body::Incoming as IncomingBody, header, Request, Response};
+use hyper::{body::Incoming as IncomingBody, header, Request, StatusCode};
+use std::net::IpAddr;
 
 use crate::request_url::RequestUrl;
 
-pub fn log_incoming_request(request: &Request<IncomingBody>) {
+pub fn log_incoming_request(request: &Request<IncomingBody>, client_ip: IpAddr) {
     let headers = request.headers().clone();
     let user_agent = headers.get(header::USER_AGENT);
     let host = headers
-        .get("host")
-        .expect("invalid host")
-        .to_str()
+        .get(header::HOST)
+        .map(|x| x.to_str().unwrap_or_default())
         .unwrap_or_default();
 
     println!(
-        "main service: request {} {} {} {:?}",
+        "main service: request {} {} {} {:?} {}",
         host,
         request.method(),
         request.uri(),
-        user_agent
+        user_agent,
+        client_ip
     );
 }
 
-pub fn log_proxy_response(request: &RequestUrl, response: &Response<IncomingBody>, latency: u128) {
+pub fn log_proxy_response(request: &RequestUrl, status: StatusCode, latency: u128) {
     println!(
         "proxy service: {:?} {}, {}mc",
         request.uri.host().unwrap_or_default(),
-        response.status(),
+        status,
         latency,
     );
 }
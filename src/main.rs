@@ -1,16 +1,34 @@
 mod chain_service;
+mod client_address;
+mod client_group;
+mod compression;
 mod config;
+mod header_policy;
+mod json_rpc;
 mod logger;
 mod metrics;
+mod metrics_exporter;
 mod metrics_service;
 mod node_service;
+mod node_switch;
+mod proxy_error;
 mod proxy_request_service;
 mod request_url;
+mod sticky_session;
+#[cfg(test)]
+mod test_helpers;
+mod upstream_errors;
+mod user_agent;
 
-use futures::future::join;
+use crate::config::MetricsConfig;
+use client_address::TrustedProxies;
+use client_group::ClientGroups;
+use futures::future;
 use hyper::server::conn::http1;
 use hyper_util::rt::TokioIo;
+use hyper_util::server::graceful::GracefulShutdown;
 use metrics::Metrics;
+use metrics_exporter::MetricsExporter;
 use metrics_service::MetricsService;
 use node_service::NodeService;
 use std::{
@@ -18,7 +36,13 @@ use std::{
     str::FromStr,
 };
 use tokio::net::TcpListener;
-use crate::config::MetricsConfig;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time::{sleep, timeout, Duration};
+use user_agent::UserAgentClassifier;
+
+// Accept fails persistently on EMFILE, retrying immediately would spin
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
@@ -34,37 +58,88 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let metrics_listener = TcpListener::bind(metrics_address).await?;
 
     let metrics_config = MetricsConfig {
-        user_agent_patterns: config.metrics.user_agent_patterns.clone(),
+        user_agents: UserAgentClassifier::new(&config.metrics.user_agent_patterns)?,
+        client_groups: ClientGroups::new(&config.metrics.client_groups)?,
+        method_labels: config.metrics.method_labels.clone(),
+        path_templates: config.path_templates_map(),
+        max_series: config.metrics.max_series,
+        latency_buckets: match &config.metrics.latency_buckets {
+            Some(buckets) => Some(buckets.get_buckets()?),
+            None => None,
+        },
+        domain_latency_buckets: config.latency_buckets_map()?,
+        latency_window: config.metrics.latency_window.clone().unwrap_or_default(),
     };
     let metrics = Metrics::new(metrics_config);
-    let node_service = NodeService::new(config.domains_map(), metrics.clone());
-    let node_service_clone = node_service.clone();
-    tokio::task::spawn(async move {
-        node_service_clone.update_block_numbers().await;
-    });
+    let trusted_proxies = TrustedProxies::new(&config.trusted_proxies)?;
+    let node_service = NodeService::new(config.domains_map(), metrics.clone(), trusted_proxies);
+    let proxy_node_service = node_service.clone();
+    let admin_node_service = node_service.clone();
+
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    let poll_tasks = node_service.update_block_numbers(shutdown_receiver.clone());
+    let purge_task = node_service.sessions.start(shutdown_receiver.clone());
+    let push_task = match &config.metrics.push {
+        Some(push) => Some(
+            MetricsExporter::new(push, metrics.clone())
+                .await?
+                .start(shutdown_receiver.clone()),
+        ),
+        None => None,
+    };
+    node_service.set_listening(true);
 
     let node_server = async move {
+        let graceful = GracefulShutdown::new();
+        let mut shutdown = shutdown_receiver;
         loop {
-            let (stream, _) = node_listener.accept().await.unwrap();
+            let (stream, remote_address) = tokio::select! {
+                result = node_listener.accept() => match result {
+                    Ok(result) => result,
+                    Err(err) => {
+                        println!("Failed to accept connection: {:?}", err);
+                        sleep(ACCEPT_ERROR_BACKOFF).await;
+                        continue;
+                    }
+                },
+                _ = shutdown.changed() => break,
+            };
             let io = TokioIo::new(stream);
 
-            let service = node_service.clone().get_proxy_request().await.clone();
+            let service = proxy_node_service.get_proxy_request(remote_address.ip());
+            let connection = graceful.watch(http1::Builder::new().serve_connection(io, service));
 
             tokio::task::spawn(async move {
-                if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                if let Err(err) = connection.await {
                     println!("Failed to serve connection: {:?}", err);
                 }
             });
         }
+        graceful
     };
 
+    let metrics_auth = config.metrics.auth.clone();
+    if metrics_auth.is_none() {
+        println!("Metrics auth is not configured, /admin/pin is disabled");
+    }
+    let compression_min_bytes = config.metrics.get_compression_min_bytes();
     let metrics_server = async move {
         loop {
-            let (stream, _) = metrics_listener.accept().await.unwrap();
+            let stream = match metrics_listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    println!("Failed to accept connection: {:?}", err);
+                    sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
             let io = TokioIo::new(stream);
 
             let metrics_service = MetricsService {
                 metrics: metrics.clone(),
+                node_service: admin_node_service.clone(),
+                auth: metrics_auth.clone(),
+                compression_min_bytes,
             };
 
             tokio::task::spawn(async move {
@@ -81,7 +156,43 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     println!("Listening node service on http://{}", node_address);
     println!("Listening metrics service on http://{}", metrics_address);
 
-    let _ret = join(node_server, metrics_server).await;
+    let node_server = tokio::task::spawn(node_server);
+    tokio::task::spawn(metrics_server);
+
+    shutdown_signal().await?;
+
+    let drain = Duration::from_secs(config.get_shutdown_drain_seconds());
+    println!("Shutting down, failing readiness for {:?}", drain);
+    node_service.set_listening(false);
+    sleep(drain).await;
+
+    println!("Draining connections");
+    shutdown_sender.send_replace(true);
+
+    let graceful = node_server.await?;
+    let shutdown_timeout = Duration::from_secs(config.get_shutdown_timeout_seconds());
+    if timeout(shutdown_timeout, graceful.shutdown())
+        .await
+        .is_err()
+    {
+        println!(
+            "Timed out draining connections after {:?}",
+            shutdown_timeout
+        );
+    }
+    future::join_all(poll_tasks).await;
+    purge_task.await?;
+    if let Some(push_task) = push_task {
+        push_task.await?;
+    }
 
     Ok(())
 }
+
+async fn shutdown_signal() -> Result<(), std::io::Error> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
@@ -1,35 +1,90 @@
-use std::sync::Arc;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use prometheus_client::encoding::text::encode;
-use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::encoding::{EncodeLabelSet, EncodeMetric, MetricEncoder, NoLabelSet};
 use prometheus_client::metrics::counter::Counter;
 use prometheus_client::metrics::family::Family;
 use prometheus_client::metrics::gauge::Gauge;
 use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::metrics::MetricType;
 use prometheus_client::registry::Registry;
-use regex::Regex;
 use crate::config::MetricsConfig;
+use crate::node_switch::NodeSwitchEvent;
+use crate::request_url::redact_url;
+use hyper::HeaderMap;
 
 #[derive(Debug, Clone)]
 pub struct Metrics {
     registry: Arc<Registry>,
-    proxy_requests: Family<ProxyRequestLabels, Counter>,
-    proxy_requests_by_user_agent: Family<ProxyRequestByAgentLabels, Gauge>,
-    proxy_response_latency: Family<ResponseLabels, Histogram>,
+    proxy_requests: Family<ProxyClientLabels, Counter>,
+    proxy_method_requests: Family<ProxyRequestLabels, Counter>,
+    proxy_requests_by_user_agent: Family<ProxyRequestByAgentLabels, Counter>,
+    proxy_response_latency: HostHistogramFamily<ResponseLabels>,
+    proxy_request_latency: HostHistogramFamily<RequestLatencyLabels>,
+    proxy_latency_window: LatencyWindowFamily,
+    proxy_request_size: Family<ProxyRequestLabels, Histogram>,
+    proxy_response_size: Family<ProxyRequestLabels, Histogram>,
+    proxy_errors: Family<ProxyErrorLabels, Counter>,
     node_host_current: Family<HostCurrentStateLabels, Gauge>,
-    #[allow(dead_code)]
+    proxy_upstream_errors: Family<UpstreamErrorLabels, Counter>,
+    proxy_consensus_disagreements: Family<HostCurrentStateLabels, Counter>,
+    proxy_hedges: Family<HedgeLabels, Counter>,
+    proxy_broadcast_accepted_first: Family<HostCurrentStateLabels, Counter>,
+    // methods admitted per host, bounded by method_labels.max_methods
+    method_labels: Arc<Mutex<HashMap<String, HashSet<String>>>>,
+    // label set hashes per family, bounded by max_series
+    series: Arc<Mutex<HashMap<&'static str, HashSet<u64>>>>,
+    metrics_series_dropped: Family<SeriesLabels, Counter>,
+    node_switch: Family<NodeSwitchLabels, Counter>,
+    node_health_signal: Family<HealthSignalLabels, Gauge>,
     node_block_latest: Family<HostStateLabels, Gauge>,
+    node_block_stale: Family<HostStateLabels, Gauge>,
     config: Arc<MetricsConfig>,
 }
 
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ProxyClientLabels {
+    host: String,
+    client: String,
+}
+
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 pub struct ProxyRequestLabels {
     host: String,
+    method: String,
+    client: String,
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 pub struct ProxyRequestByAgentLabels {
     host: String,
     user_agent: String,
+    app_version: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ProxyErrorLabels {
+    host: String,
+    error: String,
+    app_version: String,
+    client: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct UpstreamErrorLabels {
+    host: String,
+    remote_host: String,
+    error: String,
+    client: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct SeriesLabels {
+    family: String,
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
@@ -43,24 +98,305 @@ pub struct HostCurrentStateLabels {
     remote_host: String,
 }
 
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct NodeSwitchLabels {
+    host: String,
+    old_remote_host: String,
+    new_remote_host: String,
+    reason: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct HealthSignalLabels {
+    host: String,
+    remote_host: String,
+    check: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct HedgeLabels {
+    host: String,
+    remote_host: String,
+    winner: String,
+}
+
 #[derive(Clone, Hash, PartialEq, Eq, Debug, EncodeLabelSet)]
 struct ResponseLabels {
     host: String,
     remote_host: String,
     path: String,
+    method: String,
+    client: String,
+    status: u16,
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Debug, EncodeLabelSet)]
+struct RequestLatencyLabels {
+    host: String,
+    method: String,
+    client: String,
     status: u16,
 }
 
+#[derive(Clone, Hash, PartialEq, Eq, Debug, EncodeLabelSet)]
+struct LatencyWindowLabels {
+    host: String,
+    latency: String,
+}
+
+const UPSTREAM_LATENCY: &str = "upstream";
+const REQUEST_LATENCY: &str = "request";
+
+// Family constructors can't see labels, so histograms are created with the host's buckets here
+#[derive(Debug)]
+struct HostHistogramFamily<L> {
+    histograms: Arc<RwLock<HashMap<L, Histogram>>>,
+}
+
+impl<L> Clone for HostHistogramFamily<L> {
+    fn clone(&self) -> Self {
+        Self {
+            histograms: self.histograms.clone(),
+        }
+    }
+}
+
+impl<L: Clone + Hash + Eq> HostHistogramFamily<L> {
+    fn new() -> Self {
+        Self {
+            histograms: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    fn observe(&self, labels: &L, buckets: &[f64], value: f64) {
+        if let Some(histogram) = self.histograms.read().unwrap().get(labels) {
+            histogram.observe(value);
+            return;
+        }
+        self.histograms
+            .write()
+            .unwrap()
+            .entry(labels.clone())
+            .or_insert_with(|| Histogram::new(buckets.iter().copied()))
+            .observe(value);
+    }
+}
+
+impl<L: EncodeLabelSet + Debug> EncodeMetric for HostHistogramFamily<L> {
+    fn encode(&self, mut encoder: MetricEncoder) -> Result<(), std::fmt::Error> {
+        for (labels, histogram) in self.histograms.read().unwrap().iter() {
+            histogram.encode(encoder.encode_family(labels)?)?;
+        }
+        Ok(())
+    }
+
+    fn metric_type(&self) -> MetricType {
+        MetricType::Histogram
+    }
+}
+
+// Observations expire one slot at a time, so the window covers between 5/6 and all of max_age
+const LATENCY_WINDOW_SLOTS: u64 = 6;
+
+#[derive(Debug)]
+struct WindowedCounts {
+    buckets: Vec<f64>,
+    // slot index, per bucket counts with a trailing +Inf bucket, and the sum of values
+    slots: VecDeque<(u64, Vec<u64>, f64)>,
+}
+
+impl WindowedCounts {
+    fn new(buckets: &[f64]) -> Self {
+        Self {
+            buckets: buckets.to_vec(),
+            slots: VecDeque::new(),
+        }
+    }
+
+    fn expire(&mut self, slot: u64) {
+        while self
+            .slots
+            .front()
+            .is_some_and(|x| x.0 + LATENCY_WINDOW_SLOTS <= slot)
+        {
+            self.slots.pop_front();
+        }
+    }
+
+    fn observe(&mut self, slot: u64, value: f64) {
+        self.expire(slot);
+        if self.slots.back().map(|x| x.0) != Some(slot) {
+            self.slots
+                .push_back((slot, vec![0; self.buckets.len() + 1], 0.0));
+        }
+        let index = self
+            .buckets
+            .iter()
+            .position(|x| value <= *x)
+            .unwrap_or(self.buckets.len());
+        if let Some((_, counts, sum)) = self.slots.back_mut() {
+            counts[index] += 1;
+            *sum += value;
+        }
+    }
+
+    fn get_counts(&mut self, slot: u64) -> (Vec<u64>, f64) {
+        self.expire(slot);
+        let mut total = vec![0; self.buckets.len() + 1];
+        let mut total_sum = 0.0;
+        for (_, counts, sum) in &self.slots {
+            for (total, count) in total.iter_mut().zip(counts) {
+                *total += count;
+            }
+            total_sum += sum;
+        }
+        (total, total_sum)
+    }
+}
+
+// Histogram over the last max_age, windows are created with the host's buckets
+#[derive(Debug, Clone)]
+struct LatencyWindowFamily {
+    windows: Arc<Mutex<HashMap<LatencyWindowLabels, WindowedCounts>>>,
+    started: Instant,
+    slot_millis: u128,
+}
+
+impl LatencyWindowFamily {
+    fn new(max_age: Duration) -> Self {
+        Self {
+            windows: Arc::new(Mutex::new(HashMap::new())),
+            started: Instant::now(),
+            slot_millis: (max_age.as_millis() / LATENCY_WINDOW_SLOTS as u128).max(1),
+        }
+    }
+
+    fn get_slot(&self) -> u64 {
+        (self.started.elapsed().as_millis() / self.slot_millis) as u64
+    }
+
+    fn observe(&self, labels: LatencyWindowLabels, buckets: &[f64], value: f64) {
+        let slot = self.get_slot();
+        self.windows
+            .lock()
+            .unwrap()
+            .entry(labels)
+            .or_insert_with(|| WindowedCounts::new(buckets))
+            .observe(slot, value);
+    }
+
+    fn get_counts(&self, labels: &LatencyWindowLabels) -> Option<Vec<u64>> {
+        let slot = self.get_slot();
+        let mut windows = self.windows.lock().unwrap();
+        Some(windows.get_mut(labels)?.get_counts(slot).0)
+    }
+}
+
+impl EncodeMetric for LatencyWindowFamily {
+    fn encode(&self, mut encoder: MetricEncoder) -> Result<(), std::fmt::Error> {
+        let slot = self.get_slot();
+        for (labels, window) in self.windows.lock().unwrap().iter_mut() {
+            let (counts, sum) = window.get_counts(slot);
+            let buckets: Vec<(f64, u64)> = window
+                .buckets
+                .iter()
+                .copied()
+                .chain([f64::MAX])
+                .zip(counts.iter().copied())
+                .collect();
+            let count = counts.iter().sum();
+            encoder
+                .encode_family(labels)?
+                .encode_histogram::<NoLabelSet>(sum, count, &buckets, None)?;
+        }
+        Ok(())
+    }
+
+    fn metric_type(&self) -> MetricType {
+        MetricType::Histogram
+    }
+}
+
+fn latency_buckets() -> Vec<f64> {
+    exponential_buckets(50.0, 1.44, 12).collect()
+}
+
+fn size_buckets() -> Vec<f64> {
+    exponential_buckets(64.0, 4.0, 10).collect()
+}
+
+const OTHER_METHOD: &str = "other";
+
+const BASE58_ALPHABET: &str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+// Template segments starting with ":" match any single path segment
+fn is_template_match(template: &str, path: &str) -> bool {
+    let template: Vec<&str> = template.split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    template.len() == path.len()
+        && template
+            .iter()
+            .zip(path)
+            .all(|(x, segment)| x.starts_with(':') || *x == segment)
+}
+
+// The text encoder writes label values verbatim, request derived values are escaped here
+// https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md#escaping
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn template_segment(segment: &str) -> &str {
+    let is_hex = |x: &str| !x.is_empty() && x.chars().all(|c| c.is_ascii_hexdigit());
+    if !segment.is_empty() && segment.chars().all(|c| c.is_ascii_digit()) {
+        ":number"
+    } else if segment.strip_prefix("0x").is_some_and(is_hex)
+        || (segment.len() >= 32 && is_hex(segment))
+    {
+        ":hex"
+    } else if segment.len() >= 25 && segment.chars().all(|c| BASE58_ALPHABET.contains(c)) {
+        ":base58"
+    } else if segment.len() > 20 {
+        ":value"
+    } else {
+        segment
+    }
+}
+
 impl Metrics {
-    pub fn new(config: MetricsConfig) -> Self {
-        let proxy_requests = Family::<ProxyRequestLabels, Counter>::default();
-        let proxy_requests_by_user_agent = Family::<ProxyRequestByAgentLabels, Gauge>::default();
-        let proxy_response_latency =
-            Family::<ResponseLabels, Histogram>::new_with_constructor(|| {
-                Histogram::new(exponential_buckets(50.0, 1.44, 12))
+    pub fn new(mut config: MetricsConfig) -> Self {
+        config.latency_buckets.get_or_insert_with(latency_buckets);
+
+        let proxy_requests = Family::<ProxyClientLabels, Counter>::default();
+        let proxy_method_requests = Family::<ProxyRequestLabels, Counter>::default();
+        let proxy_requests_by_user_agent = Family::<ProxyRequestByAgentLabels, Counter>::default();
+        let proxy_response_latency = HostHistogramFamily::<ResponseLabels>::new();
+        let proxy_request_latency = HostHistogramFamily::<RequestLatencyLabels>::new();
+        let proxy_latency_window = LatencyWindowFamily::new(Duration::from_secs(
+            config.latency_window.get_max_age_seconds(),
+        ));
+        let proxy_request_size =
+            Family::<ProxyRequestLabels, Histogram>::new_with_constructor(|| {
+                Histogram::new(size_buckets())
+            });
+        let proxy_response_size =
+            Family::<ProxyRequestLabels, Histogram>::new_with_constructor(|| {
+                Histogram::new(size_buckets())
             });
+        let proxy_errors = Family::<ProxyErrorLabels, Counter>::default();
+        let proxy_upstream_errors = Family::<UpstreamErrorLabels, Counter>::default();
+        let proxy_consensus_disagreements = Family::<HostCurrentStateLabels, Counter>::default();
+        let proxy_hedges = Family::<HedgeLabels, Counter>::default();
+        let proxy_broadcast_accepted_first = Family::<HostCurrentStateLabels, Counter>::default();
         let node_host_current = Family::<HostCurrentStateLabels, Gauge>::default();
+        let node_switch = Family::<NodeSwitchLabels, Counter>::default();
+        let node_health_signal = Family::<HealthSignalLabels, Gauge>::default();
         let node_block_latest = Family::<HostStateLabels, Gauge>::default();
+        let node_block_stale = Family::<HostStateLabels, Gauge>::default();
+        let metrics_series_dropped = Family::<SeriesLabels, Counter>::default();
 
         let mut registry = <Registry>::with_prefix("dynode");
         registry.register(
@@ -69,114 +405,525 @@ impl Metrics {
             proxy_requests.clone(),
         );
         registry.register(
-            "proxy_requests_by_user_agent_total",
+            "proxy_method_requests",
+            "Proxy JSON-RPC calls by host and method, each batch element counts once",
+            proxy_method_requests.clone(),
+        );
+        registry.register(
+            "proxy_requests_by_user_agent",
             "Proxy requests by host and user agent",
             proxy_requests_by_user_agent.clone(),
         );
         registry.register(
             "proxy_response_latency",
-            "Proxy requests served a response by host",
+            "Upstream response latency by host and upstream",
             proxy_response_latency.clone(),
         );
+        registry.register(
+            "proxy_request_latency",
+            "End to end proxy request latency by host, including retries",
+            proxy_request_latency.clone(),
+        );
+        registry.register(
+            "proxy_latency_window",
+            "Upstream and end to end latency by host over the last max_age_seconds",
+            proxy_latency_window.clone(),
+        );
+        registry.register(
+            "proxy_request_size_bytes",
+            "Proxy request body size by host and JSON-RPC method",
+            proxy_request_size.clone(),
+        );
+        registry.register(
+            "proxy_response_size_bytes",
+            "Upstream response body size by host and JSON-RPC method",
+            proxy_response_size.clone(),
+        );
+        registry.register(
+            "proxy_errors",
+            "Proxy errors by host and error class",
+            proxy_errors.clone(),
+        );
+        registry.register(
+            "proxy_upstream_errors",
+            "Upstream error payloads by host, upstream and rule",
+            proxy_upstream_errors.clone(),
+        );
+        registry.register(
+            "proxy_consensus_disagreements",
+            "Upstreams that failed or disagreed with the consensus majority",
+            proxy_consensus_disagreements.clone(),
+        );
+        registry.register(
+            "proxy_hedges",
+            "Hedged requests by host, hedge upstream and winner",
+            proxy_hedges.clone(),
+        );
+        registry.register(
+            "proxy_broadcast_accepted_first",
+            "Broadcasts by host and the upstream that accepted first",
+            proxy_broadcast_accepted_first.clone(),
+        );
         registry.register(
             "node_host_current",
             "Node current host url",
             node_host_current.clone(),
         );
+        registry.register(
+            "node_switch",
+            "Node switches by host and reason",
+            node_switch.clone(),
+        );
+        registry.register(
+            "node_health_signal",
+            "Node health check result by host and check",
+            node_health_signal.clone(),
+        );
         registry.register(
             "node_block_latest",
             "Node block latest",
             node_block_latest.clone(),
         );
+        registry.register(
+            "node_block_stale",
+            "Node head has not advanced within max block age",
+            node_block_stale.clone(),
+        );
+        registry.register(
+            "metrics_series_dropped",
+            "Observations dropped after a family reached max series",
+            metrics_series_dropped.clone(),
+        );
 
         Self {
             registry: Arc::new(registry),
             proxy_requests,
+            proxy_method_requests,
             proxy_requests_by_user_agent,
             proxy_response_latency,
+            proxy_request_latency,
+            proxy_latency_window,
+            proxy_request_size,
+            proxy_response_size,
+            proxy_errors,
+            proxy_upstream_errors,
+            proxy_consensus_disagreements,
+            proxy_hedges,
+            proxy_broadcast_accepted_first,
+            method_labels: Arc::new(Mutex::new(HashMap::new())),
+            series: Arc::new(Mutex::new(HashMap::new())),
+            metrics_series_dropped,
             node_host_current,
+            node_switch,
+            node_health_signal,
             node_block_latest,
+            node_block_stale,
             config: Arc::new(config),
         }
     }
 
-    fn categorize_user_agent(&self, user_agent: &str) -> String {
-        for (category, patterns) in &self.config.user_agent_patterns.patterns {
-            for pattern in patterns {
-                if let Ok(re) = Regex::new(pattern) {
-                    if re.is_match(user_agent) {
-                        return category.clone();
-                    }
-                }
+    // Folds methods outside the allow list, or not yet admitted for the host, into "other"
+    fn get_method_label(&self, host: &str, method: &str) -> String {
+        if method.is_empty() {
+            return String::new();
+        }
+        let is_known = match &self.config.method_labels.allowed {
+            Some(allowed) => allowed.iter().any(|x| x == method),
+            None => self
+                .method_labels
+                .lock()
+                .unwrap()
+                .get(host)
+                .is_some_and(|x| x.contains(method)),
+        };
+        match is_known {
+            true => escape_label_value(method),
+            false => OTHER_METHOD.to_string(),
+        }
+    }
+
+    // Called once an upstream has served the methods, so made up names never take a slot
+    pub fn admit_methods(&self, host: &str, methods: &[String]) {
+        let config = &self.config.method_labels;
+        if config.allowed.is_some() {
+            return;
+        }
+        let mut method_labels = self.method_labels.lock().unwrap();
+        let admitted = method_labels.entry(host.to_string()).or_default();
+        for method in methods.iter().filter(|x| !x.is_empty()) {
+            if admitted.len() >= config.get_max_methods() {
+                break;
             }
+            admitted.insert(method.clone());
         }
-        "unknown".to_string()
     }
 
-    pub fn add_proxy_request(&self, host: &str, user_agent: &str) {
-        self.proxy_requests
-            .get_or_create(&ProxyRequestLabels {
-                host: host.to_string(),
+    // False while a response could still admit one of the methods
+    pub fn is_admitted(&self, host: &str, methods: &[String]) -> bool {
+        let config = &self.config.method_labels;
+        if config.allowed.is_some() {
+            return true;
+        }
+        let method_labels = self.method_labels.lock().unwrap();
+        match method_labels.get(host) {
+            Some(admitted) => {
+                admitted.len() >= config.get_max_methods()
+                    || methods.iter().all(|x| x.is_empty() || admitted.contains(x))
+            }
+            None => methods.iter().all(|x| x.is_empty()),
+        }
+    }
+
+    // Hard cap on label sets per family, new series beyond it are dropped and counted
+    fn is_series_allowed<L: Hash>(&self, family: &'static str, labels: &L) -> bool {
+        let mut hasher = DefaultHasher::new();
+        labels.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let mut series = self.series.lock().unwrap();
+        let series = series.entry(family).or_default();
+        if series.contains(&hash) || series.len() < self.config.get_max_series() {
+            series.insert(hash);
+            return true;
+        }
+        self.metrics_series_dropped
+            .get_or_create(&SeriesLabels {
+                family: family.to_string(),
             })
             .inc();
+        false
+    }
+
+    // Domain override, then the global buckets, defaulted in new
+    fn get_latency_buckets(&self, host: &str) -> &[f64] {
+        self.config
+            .domain_latency_buckets
+            .get(host)
+            .or(self.config.latency_buckets.as_ref())
+            .map(|x| x.as_slice())
+            .unwrap_or_default()
+    }
 
-        let categorized_agent = self.categorize_user_agent(user_agent);
-        self.proxy_requests_by_user_agent
-            .get_or_create(&ProxyRequestByAgentLabels {
+    fn add_latency_window(&self, host: &str, latency: &'static str, value: f64) {
+        let labels = LatencyWindowLabels {
+            host: host.to_string(),
+            latency: latency.to_string(),
+        };
+        if self.is_series_allowed("proxy_latency_window", &labels) {
+            self.proxy_latency_window
+                .observe(labels, self.get_latency_buckets(host), value);
+        }
+    }
+
+    pub fn get_client_group(&self, headers: &HeaderMap) -> String {
+        self.config.client_groups.get_group(headers)
+    }
+
+    pub fn add_proxy_request(
+        &self,
+        host: &str,
+        user_agent: &str,
+        client: &str,
+        methods: &[String],
+        size: usize,
+    ) {
+        let labels = ProxyClientLabels {
+            host: host.to_string(),
+            client: client.to_string(),
+        };
+        if self.is_series_allowed("proxy_requests", &labels) {
+            self.proxy_requests.get_or_create(&labels).inc();
+        }
+
+        let methods = match methods {
+            [] => vec![String::new()],
+            methods => methods
+                .iter()
+                .map(|x| self.get_method_label(host, x))
+                .collect(),
+        };
+        for method in methods.iter().filter(|x| !x.is_empty()) {
+            let labels = ProxyRequestLabels {
                 host: host.to_string(),
-                user_agent: categorized_agent,
-            })
-            .inc();
+                method: method.clone(),
+                client: client.to_string(),
+            };
+            if self.is_series_allowed("proxy_method_requests", &labels) {
+                self.proxy_method_requests.get_or_create(&labels).inc();
+            }
+        }
+
+        let labels = ProxyRequestLabels {
+            host: host.to_string(),
+            method: match methods.as_slice() {
+                [method] => method.clone(),
+                _ => "batch".to_string(),
+            },
+            client: client.to_string(),
+        };
+        if self.is_series_allowed("proxy_request_size_bytes", &labels) {
+            self.proxy_request_size
+                .get_or_create(&labels)
+                .observe(size as f64);
+        }
+
+        let labels = ProxyRequestByAgentLabels {
+            host: host.to_string(),
+            user_agent: self.config.user_agents.categorize(user_agent),
+            app_version: escape_label_value(&self.config.user_agents.get_version(user_agent)),
+        };
+        if self.is_series_allowed("proxy_requests_by_user_agent", &labels) {
+            self.proxy_requests_by_user_agent
+                .get_or_create(&labels)
+                .inc();
+        }
     }
 
-    fn truncate_path(&self, path: &str) -> String {
-        path.split('/')
-            .map(|segment| {
-                if segment.len() > 20 {
-                    ":value".to_string()
-                } else {
-                    segment.to_string()
-                }
-            })
-            .collect::<Vec<String>>()
-            .join("/")
+    fn template_path(&self, host: &str, path: &str) -> String {
+        let template = self
+            .config
+            .path_templates
+            .get(host)
+            .and_then(|templates| templates.iter().find(|x| is_template_match(x, path)));
+        match template {
+            Some(template) => template.clone(),
+            None => escape_label_value(
+                &path
+                    .split('/')
+                    .map(template_segment)
+                    .collect::<Vec<&str>>()
+                    .join("/"),
+            ),
+        }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn add_proxy_response(
         &self,
         host: &str,
         path: &str,
         remote_host: &str,
+        method: &str,
+        client: &str,
         status: u16,
         latency: u128,
+        size: usize,
     ) {
-        let path = self.truncate_path(path);
-        self.proxy_response_latency
-            .get_or_create(&ResponseLabels {
-                host: host.to_string(),
-                path,
-                remote_host: remote_host.to_string(),
-                status,
-            })
-            .observe(latency as f64);
+        let path = self.template_path(host, path);
+        let method = match method {
+            "batch" => method.to_string(),
+            method => self.get_method_label(host, method),
+        };
+        let labels = ResponseLabels {
+            host: host.to_string(),
+            path,
+            remote_host: remote_host.to_string(),
+            method: method.clone(),
+            client: client.to_string(),
+            status,
+        };
+        let buckets = self.get_latency_buckets(host);
+        if self.is_series_allowed("proxy_response_latency", &labels) {
+            self.proxy_response_latency
+                .observe(&labels, buckets, latency as f64);
+        }
+        self.add_latency_window(host, UPSTREAM_LATENCY, latency as f64);
+        let labels = ProxyRequestLabels {
+            host: host.to_string(),
+            method,
+            client: client.to_string(),
+        };
+        if self.is_series_allowed("proxy_response_size_bytes", &labels) {
+            self.proxy_response_size
+                .get_or_create(&labels)
+                .observe(size as f64);
+        }
+    }
+
+    // Upper bound of the bucket holding the recent upstream percentile,
+    // None without samples in the window or past the last bucket
+    pub fn get_proxy_latency_percentile(&self, host: &str, percentile: f64) -> Option<f64> {
+        let counts = self.proxy_latency_window.get_counts(&LatencyWindowLabels {
+            host: host.to_string(),
+            latency: UPSTREAM_LATENCY.to_string(),
+        })?;
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let target = (total as f64 * percentile).ceil() as u64;
+        let mut cumulative = 0;
+        for (bucket, count) in self.get_latency_buckets(host).iter().zip(&counts) {
+            cumulative += count;
+            if cumulative >= target {
+                return Some(*bucket);
+            }
+        }
+        None
+    }
+
+    pub fn add_proxy_request_latency(
+        &self,
+        host: &str,
+        method: &str,
+        client: &str,
+        status: u16,
+        latency: u128,
+    ) {
+        let labels = RequestLatencyLabels {
+            host: host.to_string(),
+            method: match method {
+                "batch" => method.to_string(),
+                method => self.get_method_label(host, method),
+            },
+            client: client.to_string(),
+            status,
+        };
+        if self.is_series_allowed("proxy_request_latency", &labels) {
+            self.proxy_request_latency.observe(
+                &labels,
+                self.get_latency_buckets(host),
+                latency as f64,
+            );
+        }
+        self.add_latency_window(host, REQUEST_LATENCY, latency as f64);
+    }
+
+    pub fn add_proxy_hedge(&self, host: &str, remote_host: &str, winner: &str) {
+        let labels = HedgeLabels {
+            host: host.to_string(),
+            remote_host: redact_url(remote_host),
+            winner: winner.to_string(),
+        };
+        if self.is_series_allowed("proxy_hedges", &labels) {
+            self.proxy_hedges.get_or_create(&labels).inc();
+        }
+    }
+
+    pub fn add_proxy_error(&self, host: &str, error: &str, user_agent: &str, client: &str) {
+        let labels = ProxyErrorLabels {
+            host: host.to_string(),
+            error: error.to_string(),
+            app_version: escape_label_value(&self.config.user_agents.get_version(user_agent)),
+            client: client.to_string(),
+        };
+        if self.is_series_allowed("proxy_errors", &labels) {
+            self.proxy_errors.get_or_create(&labels).inc();
+        }
+    }
+
+    pub fn add_proxy_upstream_error(
+        &self,
+        host: &str,
+        remote_host: &str,
+        error: &str,
+        client: &str,
+    ) {
+        let labels = UpstreamErrorLabels {
+            host: host.to_string(),
+            remote_host: redact_url(remote_host),
+            error: error.to_string(),
+            client: client.to_string(),
+        };
+        if self.is_series_allowed("proxy_upstream_errors", &labels) {
+            self.proxy_upstream_errors.get_or_create(&labels).inc();
+        }
+    }
+
+    pub fn add_proxy_consensus_disagreement(&self, host: &str, remote_host: &str) {
+        let labels = HostCurrentStateLabels {
+            host: host.to_string(),
+            remote_host: redact_url(remote_host),
+        };
+        if self.is_series_allowed("proxy_consensus_disagreements", &labels) {
+            self.proxy_consensus_disagreements
+                .get_or_create(&labels)
+                .inc();
+        }
+    }
+
+    pub fn add_proxy_broadcast_accepted(&self, host: &str, remote_host: &str) {
+        let labels = HostCurrentStateLabels {
+            host: host.to_string(),
+            remote_host: redact_url(remote_host),
+        };
+        if self.is_series_allowed("proxy_broadcast_accepted_first", &labels) {
+            self.proxy_broadcast_accepted_first
+                .get_or_create(&labels)
+                .inc();
+        }
     }
 
     pub fn set_node_host_current(&self, host: &str, remote_host: &str) {
-        self.node_host_current
-            .get_or_create(&HostCurrentStateLabels {
-                host: host.to_string(),
-                remote_host: remote_host.to_string(),
-            })
-            .set(1);
+        let labels = HostCurrentStateLabels {
+            host: host.to_string(),
+            remote_host: redact_url(remote_host),
+        };
+        if self.is_series_allowed("node_host_current", &labels) {
+            self.node_host_current.get_or_create(&labels).set(1);
+        }
+    }
+
+    pub fn add_node_switch(&self, event: &NodeSwitchEvent) {
+        let labels = HostCurrentStateLabels {
+            host: event.domain.clone(),
+            remote_host: event.old_url.clone(),
+        };
+        if self.is_series_allowed("node_host_current", &labels) {
+            self.node_host_current.get_or_create(&labels).set(0);
+        }
+        self.set_node_host_current(&event.domain, &event.new_url);
+
+        let labels = NodeSwitchLabels {
+            host: event.domain.clone(),
+            old_remote_host: event.old_url.clone(),
+            new_remote_host: event.new_url.clone(),
+            reason: event.reason.name().to_string(),
+        };
+        if self.is_series_allowed("node_switch", &labels) {
+            self.node_switch.get_or_create(&labels).inc();
+        }
+    }
+
+    pub fn set_node_health_signal(
+        &self,
+        host: &str,
+        remote_host: &str,
+        check: &str,
+        healthy: bool,
+    ) {
+        let labels = HealthSignalLabels {
+            host: host.to_string(),
+            remote_host: redact_url(remote_host),
+            check: check.to_string(),
+        };
+        if self.is_series_allowed("node_health_signal", &labels) {
+            self.node_health_signal
+                .get_or_create(&labels)
+                .set(healthy as i64);
+        }
     }
 
-    #[allow(dead_code)]
     pub fn set_node_block_latest(&self, host: &str, value: u64) {
-        self.node_block_latest
-            .get_or_create(&HostStateLabels {
-                host: host.to_string(),
-            })
-            .set(value as i64);
+        let labels = HostStateLabels {
+            host: host.to_string(),
+        };
+        if self.is_series_allowed("node_block_latest", &labels) {
+            self.node_block_latest
+                .get_or_create(&labels)
+                .set(value as i64);
+        }
+    }
+
+    pub fn set_node_block_stale(&self, host: &str, stale: bool) {
+        let labels = HostStateLabels {
+            host: host.to_string(),
+        };
+        if self.is_series_allowed("node_block_stale", &labels) {
+            self.node_block_stale
+                .get_or_create(&labels)
+                .set(stale as i64);
+        }
     }
 
     pub fn get_metrics(&self) -> String {
@@ -185,3 +932,184 @@ impl Metrics {
         buffer
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MethodLabels;
+
+    #[test]
+    fn test_proxy_latency_percentile() {
+        let metrics = Metrics::new(MetricsConfig::default());
+        assert_eq!(metrics.get_proxy_latency_percentile("localhost", 0.9), None);
+
+        for _ in 0..9 {
+            metrics.add_proxy_response("localhost", "/", "example.com", "", "", 200, 40, 0);
+        }
+        metrics.add_proxy_response("localhost", "/", "example.com", "", "", 200, 100, 0);
+
+        assert_eq!(
+            metrics.get_proxy_latency_percentile("localhost", 0.9),
+            Some(50.0)
+        );
+        assert_eq!(
+            metrics.get_proxy_latency_percentile("localhost", 0.99),
+            Some(latency_buckets()[2])
+        );
+    }
+
+    #[test]
+    fn test_domain_latency_buckets() {
+        let metrics = Metrics::new(MetricsConfig {
+            domain_latency_buckets: HashMap::from([("solana".to_string(), vec![5.0, 10.0, 25.0])]),
+            ..Default::default()
+        });
+        metrics.add_proxy_response("solana", "/", "example.com", "", "", 200, 7, 0);
+        metrics.add_proxy_response("localhost", "/", "example.com", "", "", 200, 7, 0);
+
+        assert_eq!(
+            metrics.get_proxy_latency_percentile("solana", 0.5),
+            Some(10.0)
+        );
+        assert_eq!(
+            metrics.get_proxy_latency_percentile("localhost", 0.5),
+            Some(50.0)
+        );
+        assert!(metrics
+            .get_metrics()
+            .contains(r#"dynode_proxy_response_latency_bucket{le="10.0",host="solana""#));
+    }
+
+    #[test]
+    fn test_latency_window() {
+        let metrics = Metrics::new(MetricsConfig::default());
+        for latency in 1..=100 {
+            metrics.add_proxy_request_latency("localhost", "eth_call", "", 200, latency);
+        }
+        let output = metrics.get_metrics();
+
+        assert!(output.contains(
+            r#"dynode_proxy_latency_window_bucket{le="50.0",host="localhost",latency="request"} 50"#
+        ));
+        assert!(output.contains(
+            r#"dynode_proxy_latency_window_count{host="localhost",latency="request"} 100"#
+        ));
+    }
+
+    #[test]
+    fn test_windowed_counts_expire() {
+        let mut window = WindowedCounts::new(&[10.0, 100.0]);
+        window.observe(0, 5.0);
+        window.observe(3, 50.0);
+        assert_eq!(window.get_counts(3), (vec![1, 1, 0], 55.0));
+        assert_eq!(window.get_counts(6), (vec![0, 1, 0], 50.0));
+        assert_eq!(window.get_counts(9), (vec![0, 0, 0], 0.0));
+    }
+
+    #[test]
+    fn test_template_path() {
+        let metrics = Metrics::new(MetricsConfig {
+            path_templates: HashMap::from([(
+                "localhost".to_string(),
+                vec!["/api/v2/address/:addr".to_string()],
+            )]),
+            ..Default::default()
+        });
+        assert_eq!(
+            metrics.template_path(
+                "localhost",
+                "/api/v2/address/bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq"
+            ),
+            "/api/v2/address/:addr"
+        );
+        assert_eq!(
+            metrics.template_path("localhost", "/api/v2/block/850000"),
+            "/api/v2/block/:number"
+        );
+        assert_eq!(
+            metrics.template_path("localhost", "/api/v2/tx/0xdeadbeef"),
+            "/api/v2/tx/:hex"
+        );
+        assert_eq!(
+            metrics.template_path(
+                "localhost",
+                "/v1/accounts/7xKXtg2CW87d97TXJSDpbD5jBkheTqA83TZRuJosgAsU"
+            ),
+            "/v1/accounts/:base58"
+        );
+        assert_eq!(
+            metrics.template_path("localhost", "/api/v2/getConsensusBlock"),
+            "/api/v2/getConsensusBlock"
+        );
+    }
+
+    #[test]
+    fn test_series_limit() {
+        let metrics = Metrics::new(MetricsConfig {
+            max_series: Some(1),
+            ..Default::default()
+        });
+        assert!(metrics.is_series_allowed("family", &"a"));
+        assert!(metrics.is_series_allowed("family", &"a"));
+        assert!(!metrics.is_series_allowed("family", &"b"));
+        assert!(metrics.is_series_allowed("other", &"b"));
+
+        metrics.add_proxy_error("a", "unsupported_domain", "", "");
+        metrics.add_proxy_error("b", "unsupported_domain", "", "");
+        let text = metrics.get_metrics();
+        assert!(text.contains("host=\"a\",error=\"unsupported_domain\""));
+        assert!(!text.contains("host=\"b\",error=\"unsupported_domain\""));
+        assert!(text.contains("metrics_series_dropped_total{family=\"proxy_errors\"} 1"));
+    }
+
+    #[test]
+    fn test_proxy_requests() {
+        let metrics = Metrics::new(MetricsConfig::default());
+        let methods = ["eth_call".to_string(), "eth_getLogs".to_string()];
+        metrics.admit_methods("localhost", &methods);
+        metrics.add_proxy_request("localhost", "", "", &methods, 100);
+        metrics.add_proxy_request("localhost", "", "", &[], 100);
+
+        // one per request, batch elements are counted by method separately
+        let text = metrics.get_metrics();
+        assert!(text.contains("dynode_proxy_requests_total{host=\"localhost\",client=\"\"} 2"));
+        assert!(text.contains(
+            "dynode_proxy_method_requests_total{host=\"localhost\",method=\"eth_call\",client=\"\"} 1"
+        ));
+        assert!(text.contains(
+            "dynode_proxy_method_requests_total{host=\"localhost\",method=\"eth_getLogs\",client=\"\"} 1"
+        ));
+    }
+
+    #[test]
+    fn test_method_label_limit() {
+        let metrics = Metrics::new(MetricsConfig {
+            method_labels: MethodLabels {
+                allowed: None,
+                max_methods: Some(1),
+            },
+            ..Default::default()
+        });
+        assert_eq!(metrics.get_method_label("localhost", "eth_call"), "other");
+        assert!(!metrics.is_admitted("localhost", &["eth_call".to_string()]));
+
+        metrics.admit_methods("localhost", &["eth_call".to_string()]);
+        assert!(metrics.is_admitted("localhost", &["eth_call".to_string()]));
+        // the only slot is taken, nothing left to admit
+        assert!(metrics.is_admitted("localhost", &["eth_getLogs".to_string()]));
+        metrics.admit_methods("localhost", &["eth_getLogs".to_string()]);
+        assert_eq!(
+            metrics.get_method_label("localhost", "eth_call"),
+            "eth_call"
+        );
+        assert_eq!(
+            metrics.get_method_label("localhost", "eth_getLogs"),
+            "other"
+        );
+        assert_eq!(
+            metrics.get_method_label("localhost", "eth_call"),
+            "eth_call"
+        );
+        assert_eq!(metrics.get_method_label("localhost", ""), "");
+    }
+}
@@ -0,0 +1,655 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{header, Method, Request};
+use hyper_tls::HttpsConnector;
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use serde_json::{json, Value};
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+use crate::config::{MetricsPush, OtlpConfig, StatsdConfig, StatsdFormat};
+use crate::metrics::Metrics;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Sample {
+    name: String,
+    labels: Vec<(String, String)>,
+    value: f64,
+}
+
+impl Sample {
+    fn get_key(&self) -> String {
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        format!("{}{{{}}}", self.name, labels.join(","))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct MetricFamily {
+    name: String,
+    metric_type: String,
+    samples: Vec<Sample>,
+}
+
+// Windowed counts go up and down, push backends aggregate the cumulative histograms instead
+const WINDOWED_FAMILIES: &[&str] = &["dynode_proxy_latency_window"];
+
+// Reads back the text exposition so pushed metrics share names and labels with the scrape
+fn parse_families(text: &str) -> Vec<MetricFamily> {
+    let mut families: Vec<MetricFamily> = vec![];
+    for line in text.lines() {
+        if let Some(descriptor) = line.strip_prefix("# TYPE ") {
+            let (name, metric_type) = descriptor.split_once(' ').unwrap_or((descriptor, ""));
+            families.push(MetricFamily {
+                name: name.to_string(),
+                metric_type: metric_type.to_string(),
+                samples: vec![],
+            });
+        } else if !line.starts_with('#') {
+            if let (Some(family), Some(sample)) = (families.last_mut(), parse_sample(line)) {
+                family.samples.push(sample);
+            }
+        }
+    }
+    families.retain(|x| !WINDOWED_FAMILIES.contains(&x.name.as_str()));
+    families
+}
+
+fn parse_sample(line: &str) -> Option<Sample> {
+    let name_end = line.find(['{', ' '])?;
+    let name = line[..name_end].to_string();
+    let mut rest = &line[name_end..];
+    let mut labels = vec![];
+
+    if let Some(label_set) = rest.strip_prefix('{') {
+        let mut position = 0;
+        loop {
+            let remaining = label_set[position..].trim_start_matches(',');
+            position = label_set.len() - remaining.len();
+            if let Some(remaining) = remaining.strip_prefix('}') {
+                rest = remaining;
+                break;
+            }
+            let (key, _) = remaining.split_once("=\"")?;
+            let mut value = String::new();
+            let mut chars = remaining[key.len() + 2..].char_indices();
+            let end = loop {
+                match chars.next()? {
+                    (_, '\\') => match chars.next()?.1 {
+                        'n' => value.push('\n'),
+                        c => value.push(c),
+                    },
+                    (index, '"') => break index,
+                    (_, c) => value.push(c),
+                }
+            };
+            labels.push((key.to_string(), value));
+            position += key.len() + 2 + end + 1;
+        }
+    }
+
+    let value = rest.split_whitespace().next()?.parse::<f64>().ok()?;
+    Some(Sample {
+        name,
+        labels,
+        value,
+    })
+}
+
+fn is_cumulative(metric_type: &str) -> bool {
+    matches!(metric_type, "counter" | "histogram")
+}
+
+#[derive(Debug)]
+pub struct StatsdExporter {
+    config: StatsdConfig,
+    socket: UdpSocket,
+    // last cumulative value per series, counters are pushed as deltas
+    previous: HashMap<String, f64>,
+}
+
+impl StatsdExporter {
+    pub async fn new(
+        config: StatsdConfig,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let address = tokio::net::lookup_host(&config.address)
+            .await?
+            .next()
+            .ok_or(format!("statsd address {} did not resolve", config.address))?;
+        let socket = match address.is_ipv4() {
+            true => UdpSocket::bind("0.0.0.0:0").await?,
+            false => UdpSocket::bind("[::]:0").await?,
+        };
+        socket.connect(address).await?;
+        Ok(Self {
+            config,
+            socket,
+            previous: HashMap::new(),
+        })
+    }
+
+    fn get_lines(&mut self, families: &[MetricFamily]) -> Vec<String> {
+        let mut lines = vec![];
+        for family in families {
+            let metric_type = match family.metric_type.as_str() {
+                "counter" | "histogram" => "c",
+                "gauge" | "unknown" => "g",
+                _ => continue,
+            };
+            for sample in &family.samples {
+                if !sample.value.is_finite() {
+                    continue;
+                }
+                let value = if is_cumulative(&family.metric_type) {
+                    let previous = self.previous.insert(sample.get_key(), sample.value);
+                    match previous {
+                        Some(previous) if previous <= sample.value => sample.value - previous,
+                        _ => sample.value,
+                    }
+                } else {
+                    sample.value
+                };
+                if metric_type == "c" && value == 0.0 {
+                    continue;
+                }
+                lines.push(self.format_line(sample, value, metric_type));
+            }
+        }
+        lines
+    }
+
+    fn format_line(&self, sample: &Sample, value: f64, metric_type: &str) -> String {
+        let sanitize = |x: &str, allowed: &[char]| -> String {
+            x.chars()
+                .map(
+                    |c| match c.is_ascii_alphanumeric() || allowed.contains(&c) {
+                        true => c,
+                        false => '_',
+                    },
+                )
+                .collect()
+        };
+        match self.config.format {
+            StatsdFormat::Statsd => {
+                let name = std::iter::once(sample.name.clone())
+                    .chain(
+                        sample
+                            .labels
+                            .iter()
+                            .filter(|x| !x.1.is_empty())
+                            .map(|x| sanitize(&x.1, &['_', '-'])),
+                    )
+                    .collect::<Vec<String>>()
+                    .join(".");
+                format!("{}:{}|{}", name, value, metric_type)
+            }
+            StatsdFormat::Dogstatsd => {
+                let tags: Vec<String> = sample
+                    .labels
+                    .iter()
+                    .map(|(key, value)| {
+                        format!("{}:{}", key, sanitize(value, &['_', '-', '.', ':', '/']))
+                    })
+                    .collect();
+                match tags.is_empty() {
+                    true => format!("{}:{}|{}", sample.name, value, metric_type),
+                    false => format!(
+                        "{}:{}|{}|#{}",
+                        sample.name,
+                        value,
+                        metric_type,
+                        tags.join(",")
+                    ),
+                }
+            }
+        }
+    }
+
+    // Newline separated lines packed into datagrams up to max_packet_size
+    fn get_packets(&self, lines: Vec<String>) -> Vec<String> {
+        let mut packets: Vec<String> = vec![];
+        for line in lines {
+            match packets.last_mut() {
+                Some(packet) if packet.len() + line.len() < self.config.get_max_packet_size() => {
+                    packet.push('\n');
+                    packet.push_str(&line);
+                }
+                _ => packets.push(line),
+            }
+        }
+        packets
+    }
+
+    pub async fn flush(
+        &mut self,
+        metrics: &Metrics,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let lines = self.get_lines(&parse_families(&metrics.get_metrics()));
+        for packet in self.get_packets(lines) {
+            self.socket.send(packet.as_bytes()).await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct HistogramSeries {
+    labels: Vec<(String, String)>,
+    // upper bound and cumulative count
+    buckets: Vec<(f64, f64)>,
+    sum: f64,
+    count: f64,
+}
+
+#[derive(Debug)]
+pub struct OtlpExporter {
+    config: OtlpConfig,
+    client: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    start_time: u128,
+}
+
+fn unix_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+fn otlp_attributes(labels: &[(String, String)]) -> Value {
+    labels
+        .iter()
+        .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+        .collect()
+}
+
+impl OtlpExporter {
+    pub fn new(config: OtlpConfig) -> Self {
+        Self {
+            config,
+            client: Client::builder(hyper_util::rt::TokioExecutor::new())
+                .build(HttpsConnector::new()),
+            start_time: unix_nanos(),
+        }
+    }
+
+    // OTLP/HTTP JSON encoding, cumulative temporality matching the Prometheus counters
+    fn get_payload(&self, families: &[MetricFamily], time: u128) -> Value {
+        let metrics: Vec<Value> = families
+            .iter()
+            .filter_map(|family| {
+                let data = match family.metric_type.as_str() {
+                    "counter" => json!({"sum": {
+                        "aggregationTemporality": 2,
+                        "isMonotonic": true,
+                        "dataPoints": self.get_number_points(family, time),
+                    }}),
+                    "gauge" | "unknown" => json!({"gauge": {
+                        "dataPoints": self.get_number_points(family, time),
+                    }}),
+                    "histogram" => json!({"histogram": {
+                        "aggregationTemporality": 2,
+                        "dataPoints": self.get_histogram_points(family, time),
+                    }}),
+                    _ => return None,
+                };
+                let mut metric = json!({"name": family.name});
+                metric.as_object_mut()?.extend(data.as_object()?.clone());
+                Some(metric)
+            })
+            .collect();
+
+        json!({"resourceMetrics": [{
+            "resource": {"attributes": [
+                {"key": "service.name", "value": {"stringValue": self.config.get_service_name()}}
+            ]},
+            "scopeMetrics": [{"scope": {"name": "dynode"}, "metrics": metrics}],
+        }]})
+    }
+
+    fn get_number_points(&self, family: &MetricFamily, time: u128) -> Vec<Value> {
+        family
+            .samples
+            .iter()
+            .filter(|x| x.value.is_finite())
+            .map(|sample| {
+                json!({
+                    "attributes": otlp_attributes(&sample.labels),
+                    "startTimeUnixNano": self.start_time.to_string(),
+                    "timeUnixNano": time.to_string(),
+                    "asDouble": sample.value,
+                })
+            })
+            .collect()
+    }
+
+    // Prometheus buckets are cumulative per "le", OTLP wants per bucket counts
+    fn get_histogram_points(&self, family: &MetricFamily, time: u128) -> Vec<Value> {
+        let mut series: Vec<HistogramSeries> = vec![];
+        for sample in &family.samples {
+            let labels: Vec<(String, String)> = sample
+                .labels
+                .iter()
+                .filter(|x| x.0 != "le")
+                .cloned()
+                .collect();
+            let index = match series.iter().position(|x| x.labels == labels) {
+                Some(index) => index,
+                None => {
+                    series.push(HistogramSeries {
+                        labels,
+                        ..Default::default()
+                    });
+                    series.len() - 1
+                }
+            };
+            let entry = &mut series[index];
+            if sample.name.ends_with("_bucket") {
+                let bound = sample
+                    .labels
+                    .iter()
+                    .find(|x| x.0 == "le")
+                    .and_then(|x| x.1.parse::<f64>().ok())
+                    .unwrap_or(f64::INFINITY);
+                entry.buckets.push((bound, sample.value));
+            } else if sample.name.ends_with("_sum") {
+                entry.sum = sample.value;
+            } else if sample.name.ends_with("_count") {
+                entry.count = sample.value;
+            }
+        }
+
+        series
+            .into_iter()
+            .map(|series| {
+                let mut previous = 0.0;
+                let bucket_counts: Vec<String> = series
+                    .buckets
+                    .iter()
+                    .map(|(_, cumulative)| {
+                        let count = cumulative - previous;
+                        previous = *cumulative;
+                        (count as u64).to_string()
+                    })
+                    .collect();
+                let bounds: Vec<f64> = series
+                    .buckets
+                    .iter()
+                    .map(|x| x.0)
+                    .filter(|x| x.is_finite())
+                    .collect();
+                json!({
+                    "attributes": otlp_attributes(&series.labels),
+                    "startTimeUnixNano": self.start_time.to_string(),
+                    "timeUnixNano": time.to_string(),
+                    "count": (series.count as u64).to_string(),
+                    "sum": series.sum,
+                    "bucketCounts": bucket_counts,
+                    "explicitBounds": bounds,
+                })
+            })
+            .collect()
+    }
+
+    pub async fn flush(
+        &self,
+        metrics: &Metrics,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let payload = self.get_payload(&parse_families(&metrics.get_metrics()), unix_nanos());
+        let request = self
+            .config
+            .headers
+            .iter()
+            .fold(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(self.config.endpoint.parse::<hyper::Uri>()?)
+                    .header(header::CONTENT_TYPE, "application/json"),
+                |builder, (key, value)| builder.header(key, value),
+            )
+            .body(Full::new(Bytes::from(serde_json::to_vec(&payload)?)))?;
+
+        // A hung collector would otherwise stall the flush loop, and shutdown with it
+        let export = async {
+            let response = self.client.request(request).await?;
+            let status = response.status();
+            let body = response.collect().await?.to_bytes();
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>((status, body))
+        };
+        let timeout_duration = Duration::from_secs(self.config.get_timeout_seconds());
+        let (status, body) = timeout(timeout_duration, export)
+            .await
+            .map_err(|_| format!("otlp export timed out after {:?}", timeout_duration))??;
+        if !status.is_success() {
+            return Err(format!(
+                "otlp export failed with {}: {}",
+                status,
+                String::from_utf8_lossy(&body)
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct MetricsExporter {
+    metrics: Metrics,
+    flush_interval: Duration,
+    statsd: Option<StatsdExporter>,
+    otlp: Option<OtlpExporter>,
+}
+
+impl MetricsExporter {
+    pub async fn new(
+        config: &MetricsPush,
+        metrics: Metrics,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let statsd = match config.statsd.clone() {
+            Some(config) => Some(StatsdExporter::new(config).await?),
+            None => None,
+        };
+        Ok(Self {
+            metrics,
+            flush_interval: Duration::from_secs(config.get_flush_interval_seconds()),
+            statsd,
+            otlp: config.otlp.clone().map(OtlpExporter::new),
+        })
+    }
+
+    pub async fn flush(&mut self) {
+        if let Some(statsd) = &mut self.statsd {
+            if let Err(err) = statsd.flush(&self.metrics).await {
+                println!("metrics exporter: statsd error: {:?}", err);
+            }
+        }
+        if let Some(otlp) = &self.otlp {
+            if let Err(err) = otlp.flush(&self.metrics).await {
+                println!("metrics exporter: otlp error: {:?}", err);
+            }
+        }
+    }
+
+    // Flushes every interval, and once more on shutdown
+    pub fn start(mut self, mut shutdown: watch::Receiver<bool>) -> JoinHandle<()> {
+        tokio::task::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = sleep(self.flush_interval) => self.flush().await,
+                    _ = shutdown.changed() => {
+                        self.flush().await;
+                        break;
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MetricsConfig;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::Response;
+    use hyper_util::rt::TokioIo;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    fn get_metrics() -> Metrics {
+        let metrics = Metrics::new(MetricsConfig::default());
+        let methods = ["eth_call".to_string()];
+        metrics.admit_methods("localhost", &methods);
+        metrics.add_proxy_request("localhost", "", "", &methods, 100);
+        metrics
+    }
+
+    #[test]
+    fn test_parse_families() {
+        let text = "# HELP dynode_node_switch Node switches.\n\
+                    # TYPE dynode_node_switch counter\n\
+                    dynode_node_switch_total{host=\"a,b\",reason=\"say \\\"hi\\\"\"} 2\n\
+                    # EOF\n";
+        assert_eq!(
+            parse_families(text),
+            vec![MetricFamily {
+                name: "dynode_node_switch".to_string(),
+                metric_type: "counter".to_string(),
+                samples: vec![Sample {
+                    name: "dynode_node_switch_total".to_string(),
+                    labels: vec![
+                        ("host".to_string(), "a,b".to_string()),
+                        ("reason".to_string(), "say \"hi\"".to_string()),
+                    ],
+                    value: 2.0,
+                }],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_families_escaped_labels() {
+        let metrics = Metrics::new(MetricsConfig::default());
+        let methods = ["a\\b \"c\"\n{d=\"e\",f} #g".to_string()];
+        metrics.admit_methods("localhost", &methods);
+        metrics.add_proxy_request("localhost", "", "", &methods, 100);
+
+        let requests = parse_families(&metrics.get_metrics())
+            .into_iter()
+            .find(|x| x.name == "dynode_proxy_method_requests")
+            .unwrap();
+        assert_eq!(
+            requests.samples[0].labels,
+            vec![
+                ("host".to_string(), "localhost".to_string()),
+                ("method".to_string(), methods[0].clone()),
+                ("client".to_string(), String::new()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_statsd_flush() {
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut exporter = StatsdExporter::new(StatsdConfig {
+            address: listener.local_addr().unwrap().to_string(),
+            format: StatsdFormat::Dogstatsd,
+            max_packet_size: None,
+        })
+        .await
+        .unwrap();
+        let metrics = get_metrics();
+
+        exporter.flush(&metrics).await.unwrap();
+        let mut buffer = [0; 2048];
+        let size = listener.recv(&mut buffer).await.unwrap();
+        let packet = String::from_utf8_lossy(&buffer[..size]).to_string();
+
+        assert!(packet.lines().any(|x| x
+            == "dynode_proxy_method_requests_total:1|c|#host:localhost,method:eth_call,client:"));
+        // unchanged counters are not pushed again
+        assert!(exporter
+            .get_lines(&parse_families(&metrics.get_metrics()))
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_otlp_flush() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        tokio::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(move |request: Request<hyper::body::Incoming>| {
+                let sender = sender.clone();
+                async move {
+                    let body = request.collect().await?.to_bytes();
+                    sender.send(body).unwrap();
+                    Ok::<_, hyper::Error>(Response::new(Full::new(Bytes::new())))
+                }
+            });
+            http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+                .unwrap();
+        });
+
+        let exporter = OtlpExporter::new(OtlpConfig {
+            endpoint: format!("http://{}/v1/metrics", address),
+            headers: HashMap::new(),
+            service_name: None,
+            timeout_seconds: None,
+        });
+        exporter.flush(&get_metrics()).await.unwrap();
+
+        let payload: Value = serde_json::from_slice(&receiver.recv().await.unwrap()).unwrap();
+        let metrics = &payload["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        let requests = metrics
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|x| x["name"] == "dynode_proxy_requests")
+            .unwrap();
+        assert_eq!(requests["sum"]["dataPoints"][0]["asDouble"], 1.0);
+
+        let size = metrics
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|x| x["name"] == "dynode_proxy_request_size_bytes")
+            .unwrap();
+        let point = &size["histogram"]["dataPoints"][0];
+        assert_eq!(point["count"], "1");
+        assert_eq!(point["sum"], 100.0);
+        assert_eq!(point["explicitBounds"][0], 64.0);
+        assert_eq!(point["bucketCounts"][1], "1");
+    }
+
+    #[tokio::test]
+    async fn test_otlp_flush_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::task::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            sleep(Duration::from_secs(10)).await;
+        });
+
+        let exporter = OtlpExporter::new(OtlpConfig {
+            endpoint: format!("http://{}/v1/metrics", address),
+            headers: HashMap::new(),
+            service_name: None,
+            timeout_seconds: Some(1),
+        });
+        let err = exporter.flush(&get_metrics()).await.unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+    }
+}
@@ -1,15 +1,42 @@
 use std::pin::Pin;
 
+use base64::{engine::general_purpose::STANDARD, Engine};
 use bytes::Bytes;
 use futures::Future;
-use http_body_util::Full;
-use hyper::{body::Incoming as IncomingBody, service::Service, Request, Response};
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::{
+    body::Incoming as IncomingBody, header, service::Service, HeaderMap, Method, Request, Response,
+    StatusCode,
+};
+use serde::Deserialize;
 
+use crate::compression;
+use crate::config::{ContentEncoding, MetricsAuth};
 use crate::metrics::Metrics;
+use crate::node_service::NodeService;
+
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+const MAX_PIN_BODY_BYTES: usize = 16 * 1024;
 
 #[derive(Debug, Clone)]
 pub struct MetricsService {
     pub metrics: Metrics,
+    pub node_service: NodeService,
+    pub auth: Option<MetricsAuth>,
+    pub compression_min_bytes: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MetricsFormat {
+    OpenMetrics,
+    Prometheus,
+}
+
+#[derive(Debug, Deserialize)]
+struct PinRequest {
+    domain: String,
+    url: Option<String>,
 }
 
 impl Service<Request<IncomingBody>> for MetricsService {
@@ -17,11 +44,312 @@ impl Service<Request<IncomingBody>> for MetricsService {
     type Error = hyper::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn call(&self, _req: Request<IncomingBody>) -> Self::Future {
-        let res = Ok(Response::builder()
-            .body(Full::new(Bytes::from(self.metrics.get_metrics())))
-            .unwrap());
+    fn call(&self, req: Request<IncomingBody>) -> Self::Future {
+        let metrics = self.metrics.clone();
+        let node_service = self.node_service.clone();
+
+        let auth = self.auth.clone();
+        let compression_min_bytes = self.compression_min_bytes;
+
+        Box::pin(async move {
+            if let Some(auth) = &auth {
+                if !Self::is_probe(req.uri().path()) && !Self::is_authorized(auth, req.headers()) {
+                    return Ok(Self::unauthorized_response(auth));
+                }
+            }
+
+            match (req.method(), req.uri().path()) {
+                (&Method::GET, "/metrics") | (&Method::HEAD, "/metrics") => Ok(
+                    Self::metrics_response(&metrics, req.headers(), compression_min_bytes),
+                ),
+                (_, "/metrics") => Ok(Self::status_response(StatusCode::METHOD_NOT_ALLOWED)),
+                (&Method::GET, "/health") => Ok(Self::status_response(StatusCode::OK)),
+                (&Method::GET, "/ready") => {
+                    if node_service.is_ready().await {
+                        Ok(Self::status_response(StatusCode::OK))
+                    } else {
+                        Ok(Self::status_response(StatusCode::SERVICE_UNAVAILABLE))
+                    }
+                }
+                (&Method::GET, "/ready/domains") => {
+                    let domains = node_service.get_domains_readiness().await;
+                    Ok(Self::json_response(StatusCode::OK, &domains))
+                }
+                (&Method::GET, path) if path.starts_with("/ready/domains/") => {
+                    let name = path.trim_start_matches("/ready/domains/");
+                    let domains = node_service.get_domains_readiness().await;
+                    match domains.into_iter().find(|x| x.domain == name) {
+                        Some(domain) if domain.ready => {
+                            Ok(Self::json_response(StatusCode::OK, &domain))
+                        }
+                        Some(domain) => Ok(Self::json_response(
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            &domain,
+                        )),
+                        None => Ok(Self::status_response(StatusCode::NOT_FOUND)),
+                    }
+                }
+                (&Method::GET, "/admin/node_switches") => {
+                    let events = node_service.get_node_switches().await;
+                    Ok(Self::json_response(StatusCode::OK, &events))
+                }
+                // routing changes are refused unless credentials were checked above
+                (&Method::POST, "/admin/pin") | (&Method::DELETE, "/admin/pin")
+                    if auth.is_none() =>
+                {
+                    Ok(Self::status_response(StatusCode::FORBIDDEN))
+                }
+                (&Method::POST, "/admin/pin") | (&Method::DELETE, "/admin/pin") => {
+                    let method = req.method().clone();
+                    let body = match Limited::new(req.into_body(), MAX_PIN_BODY_BYTES)
+                        .collect()
+                        .await
+                    {
+                        Ok(body) => body.to_bytes(),
+                        Err(err) if err.is::<LengthLimitError>() => {
+                            return Ok(Self::status_response(StatusCode::PAYLOAD_TOO_LARGE));
+                        }
+                        Err(_) => return Ok(Self::status_response(StatusCode::BAD_REQUEST)),
+                    };
+                    let Ok(pin) = serde_json::from_slice::<PinRequest>(&body) else {
+                        return Ok(Self::status_response(StatusCode::BAD_REQUEST));
+                    };
+                    let updated = match (method, pin.url) {
+                        (Method::POST, Some(url)) => node_service.pin_node(&pin.domain, &url).await,
+                        (Method::DELETE, _) => node_service.unpin_node(&pin.domain).await,
+                        _ => return Ok(Self::status_response(StatusCode::BAD_REQUEST)),
+                    };
+                    if updated {
+                        Ok(Self::status_response(StatusCode::OK))
+                    } else {
+                        Ok(Self::status_response(StatusCode::NOT_FOUND))
+                    }
+                }
+                _ => Ok(Self::status_response(StatusCode::NOT_FOUND)),
+            }
+        })
+    }
+}
+
+impl MetricsService {
+    fn metrics_response(
+        metrics: &Metrics,
+        headers: &HeaderMap,
+        compression_min_bytes: usize,
+    ) -> Response<Full<Bytes>> {
+        let format = Self::get_metrics_format(headers);
+        let body = match format {
+            MetricsFormat::OpenMetrics => metrics.get_metrics(),
+            MetricsFormat::Prometheus => Self::to_prometheus_text(&metrics.get_metrics()),
+        };
+        let content_type = match format {
+            MetricsFormat::OpenMetrics => OPENMETRICS_CONTENT_TYPE,
+            MetricsFormat::Prometheus => PROMETHEUS_CONTENT_TYPE,
+        };
+        let builder = Response::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::VARY, "Accept, Accept-Encoding");
+
+        if body.len() >= compression_min_bytes && Self::accepts_gzip(headers) {
+            if let Ok(body) = compression::encode(ContentEncoding::Gzip, body.as_bytes()) {
+                return builder
+                    .header(header::CONTENT_ENCODING, "gzip")
+                    .body(Full::new(Bytes::from(body)))
+                    .unwrap();
+            }
+        }
+        builder.body(Full::new(Bytes::from(body))).unwrap()
+    }
+
+    // Highest q value wins, Prometheus text unless OpenMetrics is preferred
+    fn get_metrics_format(headers: &HeaderMap) -> MetricsFormat {
+        let accept = headers
+            .get(header::ACCEPT)
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or_default();
+        let mut openmetrics = 0.0;
+        let mut prometheus = 0.0;
+        for (media_type, quality) in compression::parse_quality_list(accept) {
+            match media_type.as_str() {
+                "application/openmetrics-text" => openmetrics = f64::max(openmetrics, quality),
+                "text/plain" | "text/*" | "*/*" => prometheus = f64::max(prometheus, quality),
+                _ => {}
+            }
+        }
+        match openmetrics > 0.0 && openmetrics >= prometheus {
+            true => MetricsFormat::OpenMetrics,
+            false => MetricsFormat::Prometheus,
+        }
+    }
+
+    fn accepts_gzip(headers: &HeaderMap) -> bool {
+        let accept_encoding = headers
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or_default();
+        compression::negotiate(accept_encoding, &[ContentEncoding::Gzip]).is_some()
+    }
+
+    // The registry encodes OpenMetrics, the 0.0.4 text format has no EOF, unit or unknown type
+    fn to_prometheus_text(openmetrics: &str) -> String {
+        openmetrics
+            .lines()
+            .filter(|x| *x != "# EOF" && !x.starts_with("# UNIT "))
+            .map(|x| match x.strip_prefix("# TYPE ") {
+                Some(descriptor) if descriptor.ends_with(" unknown") => {
+                    format!(
+                        "# TYPE {} untyped\n",
+                        descriptor.trim_end_matches(" unknown")
+                    )
+                }
+                _ => format!("{}\n", x),
+            })
+            .collect()
+    }
+
+    // Only the bare probes stay open, the per domain detail lists upstreams
+    fn is_probe(path: &str) -> bool {
+        path == "/health" || path == "/ready"
+    }
+
+    fn is_authorized(auth: &MetricsAuth, headers: &HeaderMap) -> bool {
+        let Some(authorization) = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|x| x.to_str().ok())
+        else {
+            return false;
+        };
+        if let (Some(token), Some(value)) =
+            (&auth.bearer_token, authorization.strip_prefix("Bearer "))
+        {
+            if Self::is_equal(token.as_bytes(), value.trim().as_bytes()) {
+                return true;
+            }
+        }
+        if let (Some(username), Some(password), Some(value)) = (
+            &auth.username,
+            &auth.password,
+            authorization.strip_prefix("Basic "),
+        ) {
+            let expected = STANDARD.encode(format!("{}:{}", username, password));
+            return Self::is_equal(expected.as_bytes(), value.trim().as_bytes());
+        }
+        false
+    }
+
+    // Constant time comparison so credentials can't be guessed byte by byte
+    fn is_equal(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+    }
+
+    fn unauthorized_response(auth: &MetricsAuth) -> Response<Full<Bytes>> {
+        let challenge = match auth.username.is_some() {
+            true => "Basic realm=\"dynode\"",
+            false => "Bearer",
+        };
+        let mut response = Self::status_response(StatusCode::UNAUTHORIZED);
+        response.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            header::HeaderValue::from_static(challenge),
+        );
+        response
+    }
+
+    fn json_response<T: serde::Serialize>(status: StatusCode, value: &T) -> Response<Full<Bytes>> {
+        Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(
+                serde_json::to_vec(value).unwrap_or_default(),
+            )))
+            .unwrap()
+    }
+
+    fn status_response(status: StatusCode) -> Response<Full<Bytes>> {
+        Response::builder()
+            .status(status)
+            .body(Full::new(Bytes::from(
+                status.canonical_reason().unwrap_or_default(),
+            )))
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::headers;
+
+    #[test]
+    fn test_get_metrics_format() {
+        let prometheus = "application/openmetrics-text;version=1.0.0,application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+        assert_eq!(
+            MetricsService::get_metrics_format(&headers(&[("accept", prometheus)])),
+            MetricsFormat::OpenMetrics
+        );
+        assert_eq!(
+            MetricsService::get_metrics_format(&headers(&[(
+                "accept",
+                "application/openmetrics-text;q=0.2,text/plain"
+            )])),
+            MetricsFormat::Prometheus
+        );
+        assert_eq!(
+            MetricsService::get_metrics_format(&HeaderMap::new()),
+            MetricsFormat::Prometheus
+        );
+    }
+
+    #[test]
+    fn test_accepts_gzip() {
+        assert!(MetricsService::accepts_gzip(&headers(&[(
+            "accept-encoding",
+            "deflate, gzip"
+        )])));
+        assert!(!MetricsService::accepts_gzip(&headers(&[(
+            "accept-encoding",
+            "gzip;q=0"
+        )])));
+    }
+
+    #[test]
+    fn test_to_prometheus_text() {
+        let openmetrics = "# HELP a A.\n# TYPE a unknown\na 1\n# EOF\n";
+        assert_eq!(
+            MetricsService::to_prometheus_text(openmetrics),
+            "# HELP a A.\n# TYPE a untyped\na 1\n"
+        );
+    }
+
+    #[test]
+    fn test_is_probe() {
+        assert!(MetricsService::is_probe("/health"));
+        assert!(MetricsService::is_probe("/ready"));
+        assert!(!MetricsService::is_probe("/ready/domains"));
+        assert!(!MetricsService::is_probe("/ready/domains/localhost:3000"));
+        assert!(!MetricsService::is_probe("/metrics"));
+    }
 
-        Box::pin(async { res })
+    #[test]
+    fn test_is_authorized() {
+        let auth = MetricsAuth {
+            username: Some("prometheus".to_string()),
+            password: Some("secret".to_string()),
+            bearer_token: Some("token".to_string()),
+        };
+        let basic = format!("Basic {}", STANDARD.encode("prometheus:secret"));
+        assert!(MetricsService::is_authorized(
+            &auth,
+            &headers(&[("authorization", &basic)])
+        ));
+        assert!(MetricsService::is_authorized(
+            &auth,
+            &headers(&[("authorization", "Bearer token")])
+        ));
+        assert!(!MetricsService::is_authorized(
+            &auth,
+            &headers(&[("authorization", "Bearer other")])
+        ));
+        assert!(!MetricsService::is_authorized(&auth, &HeaderMap::new()));
     }
 }
@@ -1,13 +1,21 @@
-use std::str::FromStr;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::{collections::HashMap, sync::Arc, time::Instant};
 
 use futures::future;
-use tokio::sync::Mutex;
+use serde::Serialize;
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
 use tokio::time::{sleep, Duration};
 
+use crate::chain_service::probe::{HealthCheck, HealthSignal, Probe};
+use crate::client_address::TrustedProxies;
 use crate::config::Url;
 use crate::metrics::Metrics;
-use primitives::ChainType;
+use crate::node_switch::{NodeSwitchEvent, NodeSwitchHistory, NodeSwitchReason};
+use crate::request_url::redact_url;
+use crate::sticky_session::StickySessions;
+use crate::upstream_errors::UpstreamErrors;
 use crate::{
     chain_service::ChainService,
     config::Domain,
@@ -16,9 +24,18 @@ use crate::{
 
 #[derive(Debug, Clone)]
 pub struct NodeService {
-    pub domains: HashMap<String, Domain>,
+    pub domains: Arc<HashMap<String, Domain>>,
     pub nodes: Arc<Mutex<HashMap<String, NodeDomain>>>,
     pub metrics: Arc<Metrics>,
+    pub history: NodeSwitchHistory,
+    pub pins: Arc<Mutex<HashMap<String, Url>>>,
+    pub results: Arc<Mutex<HashMap<String, Vec<NodeResult>>>>,
+    pub heads: Arc<Mutex<HashMap<String, NodeHead>>>,
+    pub identities: Arc<Mutex<HashMap<String, HashMap<String, bool>>>>,
+    pub sessions: StickySessions,
+    pub upstream_errors: UpstreamErrors,
+    pub trusted_proxies: TrustedProxies,
+    pub listening: Arc<AtomicBool>,
 }
 
 #[derive(Debug)]
@@ -26,6 +43,8 @@ pub struct NodeRawResult {
     pub url: Url,
     pub result: Result<u64, Box<dyn std::error::Error + Send + Sync>>,
     pub latency: u64,
+    pub signals: Vec<HealthSignal>,
+    pub identity: Option<bool>,
 }
 
 #[derive(Debug, Clone)]
@@ -35,27 +54,75 @@ pub struct NodeResult {
     pub latency: u64,
 }
 
+#[derive(Debug, Clone)]
+pub struct NodeHead {
+    pub block_number: u64,
+    pub updated_at: Instant,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DomainReadiness {
+    pub domain: String,
+    pub ready: bool,
+    pub pollable: bool,
+    pub stale: bool,
+    // false until the chain identity of the current upstream is confirmed
+    pub verified: bool,
+    pub head_age_seconds: Option<u64>,
+    pub current_url: Option<String>,
+    pub healthy_urls: Vec<String>,
+    pub block_number: Option<u64>,
+}
+
 impl NodeService {
-    pub fn new(domains: HashMap<String, Domain>, metrics: Metrics) -> Self {
+    pub fn new(
+        domains: HashMap<String, Domain>,
+        metrics: Metrics,
+        trusted_proxies: TrustedProxies,
+    ) -> Self {
         //
         let mut hash_map: HashMap<String, NodeDomain> = HashMap::new();
 
         for (key, domain) in domains.clone() {
             let url = domain.urls.first().unwrap().clone();
-            hash_map.insert(key, NodeDomain { url });
+            let verified = domain.get_chain_identity().is_none();
+            let urls = domain.urls.clone();
+            hash_map.insert(
+                key,
+                NodeDomain {
+                    url,
+                    verified,
+                    urls,
+                    block_number: None,
+                },
+            );
         }
 
         Self {
-            domains,
+            domains: Arc::new(domains),
             nodes: Arc::new(Mutex::new(hash_map)),
             metrics: Arc::new(metrics),
+            history: NodeSwitchHistory::default(),
+            pins: Arc::new(Mutex::new(HashMap::new())),
+            results: Arc::new(Mutex::new(HashMap::new())),
+            heads: Arc::new(Mutex::new(HashMap::new())),
+            identities: Arc::new(Mutex::new(HashMap::new())),
+            sessions: StickySessions::default(),
+            upstream_errors: UpstreamErrors::default(),
+            trusted_proxies,
+            listening: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    pub async fn get_proxy_request(&self) -> ProxyRequestService {
+    pub fn get_proxy_request(&self, remote_ip: IpAddr) -> ProxyRequestService {
         ProxyRequestService {
-            domains: self.get_node_domains().await,
+            nodes: self.nodes.clone(),
+            domain_configs: self.domains.clone(),
             metrics: self.metrics.as_ref().clone(),
+            sessions: self.sessions.clone(),
+            upstream_errors: self.upstream_errors.clone(),
+            trusted_proxies: self.trusted_proxies.clone(),
+            remote_ip,
         }
     }
 
@@ -79,128 +146,437 @@ impl NodeService {
         (*self.nodes.lock().await).clone()
     }
 
-    pub async fn update_block_numbers(&self) {
-        for (_, domain) in self.domains.clone() {
-            self.metrics
-                .set_node_host_current(&domain.domain, &domain.urls.first().unwrap().url);
-
-            if domain.urls.len() > 1 {
-                let domain = domain.clone();
-
-                let nodes = Arc::clone(&self.nodes);
-                //let metrics = Arc::clone(&self.metrics);
+    pub fn update_block_numbers(&self, shutdown: watch::Receiver<bool>) -> Vec<JoinHandle<()>> {
+        self.domains
+            .values()
+            .cloned()
+            .map(|domain| {
+                self.metrics
+                    .set_node_host_current(&domain.domain, &domain.urls.first().unwrap().url);
 
+                let service = self.clone();
+                let mut shutdown = shutdown.clone();
                 tokio::task::spawn(async move {
                     loop {
-                        let tasks: Vec<_> = domain
-                            .clone()
-                            .urls
-                            .iter()
-                            .flat_map(|url| {
-                                let chain_type = domain.chain_type.clone();
-                                let url = url.clone();
-                                if let Ok(chain_type) = ChainType::from_str(&chain_type) {
-                                    Some(tokio::spawn(async move {
-                                        let now = Instant::now();
-                                        let result = Self::get_latest_block(chain_type, url.url.as_str()).await;
-
-                                        NodeRawResult {
-                                            url: url.clone(),
-                                            result,
-                                            latency: now.elapsed().as_millis() as u64,
-                                        }
-                                    }))
-                                } else {
-                                    None
-                                }
-                            })
-                            .collect();
-
-                        let results: Vec<NodeResult> = future::join_all(tasks)
-                            .await
-                            .into_iter()
-                            .filter_map(|res| res.ok())
-                            .filter_map(|res| {
-                                res.result.ok().map(|block_number| NodeResult {
-                                    url: res.url,
-                                    block_number,
-                                    latency: res.latency,
-                                })
-                            })
-                            .collect();
-
-                        if let Some(value) =
-                            Self::get_node_domain(&nodes.clone(), domain.domain.clone()).await
-                        {
-                            let is_url_behind =
-                                domain.is_url_behind(value.url.clone(), results.clone());
-
-                            println!(
-                                "node service: {} is behind: {}, {:?}",
-                                value.url.url,
-                                is_url_behind,
-                                results
-                                    .clone()
-                                    .into_iter()
-                                    .map(|x| x.block_number)
-                                    .collect::<Vec<u64>>()
-                            );
-                            if is_url_behind {
-                                if let Some(node) =
-                                    Domain::find_highest_block_number(results.clone())
-                                {
-                                    Self::update_node_domain(
-                                        &nodes,
-                                        domain.domain.clone(),
-                                        NodeDomain {
-                                            url: node.url.clone(),
-                                        },
-                                    )
-                                    .await;
-
-                                    println!(
-                                        "node service: {:?} set new node: {}, latest: {}mc",
-                                        domain.domain.clone(),
-                                        node.url.url.clone(),
-                                        node.latency
-                                    );
-                                }
-                            }
+                        tokio::select! {
+                            _ = service.poll_domain(&domain) => {}
+                            _ = shutdown.changed() => break,
+                        }
+                        tokio::select! {
+                            _ = sleep(Duration::from_secs(domain.get_poll_interval_seconds())) => {}
+                            _ = shutdown.changed() => break,
                         }
+                    }
+                })
+            })
+            .collect()
+    }
+
+    async fn poll_domain(&self, domain: &Domain) {
+        let Some(probe) = domain.get_probe() else {
+            return;
+        };
+        let checks = domain.get_health_checks();
+        let chain_identity = domain.get_chain_identity();
+        let tasks: Vec<_> = domain
+            .urls
+            .iter()
+            .map(|url| {
+                let probe = probe.clone();
+                let checks = checks.clone();
+                let chain_identity = chain_identity.clone();
+                let url = url.clone();
+                tokio::spawn(async move {
+                    let now = Instant::now();
+                    let chain_service = Self::get_chain_service(probe, &url);
+                    let result = chain_service.get_block_number().await;
+                    let latency = now.elapsed().as_millis() as u64;
+                    let signals = match result {
+                        Ok(_) => chain_service.get_health_signals(&checks).await,
+                        Err(_) => vec![],
+                    };
+                    // Transport errors leave the identity unknown rather than mismatched
+                    let identity = match (&result, chain_identity) {
+                        (Ok(_), Some((method, expected))) => chain_service
+                            .get_chain_identity(method)
+                            .await
+                            .ok()
+                            .map(|actual| method.matches(&expected, &actual)),
+                        _ => None,
+                    };
 
-                        sleep(Duration::from_secs(domain.get_poll_interval_seconds())).await;
+                    NodeRawResult {
+                        url: url.clone(),
+                        result,
+                        latency,
+                        signals,
+                        identity,
                     }
+                })
+            })
+            .collect();
+
+        let mut raw_results: Vec<NodeRawResult> = future::join_all(tasks)
+            .await
+            .into_iter()
+            .filter_map(|res| res.ok())
+            .collect();
+
+        if chain_identity.is_some() {
+            let mut identities = self.identities.lock().await;
+            let identities = identities.entry(domain.domain.clone()).or_default();
+            for res in raw_results.iter_mut().filter(|x| x.result.is_ok()) {
+                if let Some(identity) = res.identity {
+                    identities.insert(res.url.url.clone(), identity);
+                }
+                res.signals.push(HealthSignal {
+                    check: HealthCheck::ChainId,
+                    healthy: identities.get(&res.url.url).copied().unwrap_or(false),
+                });
+            }
+        }
+
+        let error_counts = self.upstream_errors.take(&domain.domain).await;
+        if let Some(classification) = &domain.error_classification {
+            for res in raw_results.iter_mut().filter(|x| x.result.is_ok()) {
+                let count = error_counts.get(&res.url.url).copied().unwrap_or_default();
+                res.signals.push(HealthSignal {
+                    check: HealthCheck::ErrorResponses,
+                    healthy: count < classification.get_eject_threshold(),
                 });
             }
         }
+
+        let mut failed_checks: HashMap<String, HealthCheck> = HashMap::new();
+        let mut verified: HashMap<String, bool> = HashMap::new();
+        for res in &raw_results {
+            if res.result.is_ok() {
+                verified.insert(res.url.url.clone(), true);
+            }
+            for signal in &res.signals {
+                self.metrics.set_node_health_signal(
+                    &domain.domain,
+                    &res.url.url,
+                    signal.check.name(),
+                    signal.healthy,
+                );
+                if !signal.healthy {
+                    failed_checks.insert(res.url.url.clone(), signal.check);
+                }
+                if !signal.healthy && signal.check == HealthCheck::ChainId {
+                    println!(
+                        "node service: {} chain identity mismatch for {}",
+                        domain.domain, res.url.url
+                    );
+                    verified.insert(res.url.url.clone(), false);
+                }
+            }
+        }
+
+        let results: Vec<NodeResult> = raw_results
+            .into_iter()
+            .filter(|res| !failed_checks.contains_key(&res.url.url))
+            .filter_map(|res| {
+                res.result.ok().map(|block_number| NodeResult {
+                    url: res.url,
+                    block_number,
+                    latency: res.latency,
+                })
+            })
+            .collect();
+
+        self.results
+            .lock()
+            .await
+            .insert(domain.domain.clone(), results.clone());
+        self.update_head(domain, &results).await;
+
+        let Some(value) = Self::get_node_domain(&self.nodes, domain.domain.clone()).await else {
+            return;
+        };
+        let node_domain = NodeDomain {
+            verified: verified
+                .get(&value.url.url)
+                .copied()
+                .unwrap_or(value.verified),
+            urls: Self::get_healthy_urls(domain, &results),
+            block_number: Domain::find_highest_block_number(results.clone())
+                .map(|x| x.block_number),
+            ..value.clone()
+        };
+        Self::update_node_domain(&self.nodes, domain.domain.clone(), node_domain).await;
+        let is_url_behind = domain.is_url_behind(value.url.clone(), results.clone());
+
+        println!(
+            "node service: {} is behind: {}, {:?}",
+            value.url.url,
+            is_url_behind,
+            results
+                .clone()
+                .into_iter()
+                .map(|x| x.block_number)
+                .collect::<Vec<u64>>()
+        );
+
+        if !is_url_behind || self.pins.lock().await.contains_key(&domain.domain) {
+            return;
+        }
+
+        if let Some(node) = Domain::find_highest_block_number(results.clone()) {
+            let reason = match results.iter().find(|x| x.url == value.url) {
+                Some(current) => NodeSwitchReason::Behind {
+                    blocks: node.block_number.saturating_sub(current.block_number),
+                },
+                None => match failed_checks.get(&value.url.url) {
+                    Some(check) => NodeSwitchReason::HealthEjection {
+                        check: check.name().to_string(),
+                    },
+                    None => NodeSwitchReason::PollFailure,
+                },
+            };
+            self.switch_node(&domain.domain, &value.url, node.url.clone(), reason)
+                .await;
+
+            println!(
+                "node service: {:?} set new node: {}, latest: {}mc",
+                domain.domain.clone(),
+                node.url.url.clone(),
+                node.latency
+            );
+        }
     }
 
-    pub async fn get_latest_block(
-        chain_type: ChainType,
-        url: &str,
-    ) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
-        let chain_service = ChainService {
-            chain_type: chain_type.clone(),
-            url: url.to_string(),
+    // Ranked by block number, then latency, excluding urls behind by more than block_delay
+    fn get_healthy_urls(domain: &Domain, results: &[NodeResult]) -> Vec<Url> {
+        let mut results: Vec<NodeResult> = results
+            .iter()
+            .filter(|x| !domain.is_url_behind(x.url.clone(), results.to_vec()))
+            .cloned()
+            .collect();
+        results.sort_by(|a, b| {
+            b.block_number
+                .cmp(&a.block_number)
+                .then(a.latency.cmp(&b.latency))
+        });
+        results.into_iter().map(|x| x.url).collect()
+    }
+
+    async fn update_head(&self, domain: &Domain, results: &[NodeResult]) {
+        let mut heads = self.heads.lock().await;
+        if let Some(node) = Domain::find_highest_block_number(results.to_vec()) {
+            self.metrics
+                .set_node_block_latest(&domain.domain, node.block_number);
+
+            let advanced = heads
+                .get(&domain.domain)
+                .map(|head| node.block_number > head.block_number)
+                .unwrap_or(true);
+            if advanced {
+                heads.insert(
+                    domain.domain.clone(),
+                    NodeHead {
+                        block_number: node.block_number,
+                        updated_at: Instant::now(),
+                    },
+                );
+            }
+        }
+
+        let is_stale = domain.is_head_stale(heads.get(&domain.domain).map(|x| x.updated_at));
+        self.metrics.set_node_block_stale(&domain.domain, is_stale);
+        if is_stale {
+            println!(
+                "node service: {} head has not advanced in {}s",
+                domain.domain,
+                domain.max_block_age_seconds.unwrap_or_default()
+            );
+        }
+    }
+
+    async fn switch_node(
+        &self,
+        domain: &str,
+        old_url: &Url,
+        new_url: Url,
+        reason: NodeSwitchReason,
+    ) {
+        let event = NodeSwitchEvent::new(domain, &old_url.url, &new_url.url, reason);
+        println!(
+            "node service: {} switch {} -> {}, reason: {}",
+            event.domain, event.old_url, event.new_url, event.reason
+        );
+
+        let verified = self.is_url_verified(domain, &new_url).await;
+        let current = Self::get_node_domain(&self.nodes, domain.to_string()).await;
+        Self::update_node_domain(
+            &self.nodes,
+            domain.to_string(),
+            NodeDomain {
+                url: new_url,
+                verified,
+                urls: current.clone().map(|x| x.urls).unwrap_or_default(),
+                block_number: current.and_then(|x| x.block_number),
+            },
+        )
+        .await;
+        self.metrics.add_node_switch(&event);
+        self.history.add(event).await;
+    }
+
+    async fn is_url_verified(&self, domain: &str, url: &Url) -> bool {
+        let has_identity = self
+            .domains
+            .get(domain)
+            .is_some_and(|x| x.get_chain_identity().is_some());
+        !has_identity
+            || self
+                .results
+                .lock()
+                .await
+                .get(domain)
+                .is_some_and(|results| results.iter().any(|x| &x.url == url))
+    }
+
+    pub async fn pin_node(&self, domain: &str, url: &str) -> bool {
+        let Some(new_url) = self
+            .domains
+            .get(domain)
+            .and_then(|x| x.urls.iter().find(|x| x.url == url))
+            .cloned()
+        else {
+            return false;
         };
-        chain_service.get_block_number().await
+        self.pins
+            .lock()
+            .await
+            .insert(domain.to_string(), new_url.clone());
+
+        if let Some(current) = Self::get_node_domain(&self.nodes, domain.to_string()).await {
+            if current.url != new_url {
+                self.switch_node(domain, &current.url, new_url, NodeSwitchReason::ManualPin)
+                    .await;
+            }
+        }
+        true
+    }
+
+    pub async fn unpin_node(&self, domain: &str) -> bool {
+        self.pins.lock().await.remove(domain).is_some()
+    }
+
+    pub fn set_listening(&self, listening: bool) {
+        self.listening.store(listening, Ordering::SeqCst);
+    }
+
+    // A stale head on one chain should not pull the whole proxy out of rotation,
+    // staleness is reported per domain and in metrics instead
+    pub async fn is_ready(&self) -> bool {
+        self.listening.load(Ordering::SeqCst)
+            && self
+                .get_domains_readiness()
+                .await
+                .iter()
+                .all(|x| x.verified && (!x.healthy_urls.is_empty() || !x.pollable))
+    }
+
+    pub async fn get_domains_readiness(&self) -> Vec<DomainReadiness> {
+        let nodes = self.get_node_domains().await;
+        let results = self.results.lock().await;
+        let heads = self.heads.lock().await;
+
+        let mut domains: Vec<DomainReadiness> = self
+            .domains
+            .values()
+            .map(|domain| {
+                let results = results.get(&domain.domain).cloned().unwrap_or_default();
+                let updated_at = heads.get(&domain.domain).map(|x| x.updated_at);
+                let stale = domain.is_head_stale(updated_at);
+                let verified = nodes.get(&domain.domain).is_some_and(|x| x.verified);
+                DomainReadiness {
+                    domain: domain.domain.clone(),
+                    ready: !results.is_empty() && !stale && verified,
+                    pollable: domain.get_probe().is_some(),
+                    stale,
+                    verified,
+                    head_age_seconds: updated_at.map(|x| x.elapsed().as_secs()),
+                    current_url: nodes.get(&domain.domain).map(|x| redact_url(&x.url.url)),
+                    healthy_urls: results.iter().map(|x| redact_url(&x.url.url)).collect(),
+                    block_number: Domain::find_highest_block_number(results)
+                        .map(|x| x.block_number),
+                }
+            })
+            .collect();
+        domains.sort_by(|a, b| a.domain.cmp(&b.domain));
+        domains
+    }
+
+    pub async fn get_node_switches(&self) -> Vec<NodeSwitchEvent> {
+        self.history.get_events().await
+    }
+
+    fn get_chain_service(probe: Probe, url: &Url) -> ChainService {
+        ChainService {
+            probe,
+            url: url.url.clone(),
+            headers: url.headers.clone().unwrap_or_default(),
+        }
     }
 
     #[allow(dead_code)]
-    pub async fn update_latest_block(chain_type: ChainType, url: &str) {
-        let chain_service = ChainService {
-            chain_type: chain_type.clone(),
-            url: url.to_string(),
-        };
+    pub async fn update_latest_block(probe: Probe, url: &Url) {
+        let chain_service = Self::get_chain_service(probe.clone(), url);
         let now = Instant::now();
         let res = chain_service.get_block_number().await;
 
         println!(
-            "update_latest_block: chain_type: {:?}, url: {} {:?}, {}ms",
-            chain_type,
-            url,
+            "update_latest_block: probe: {:?}, url: {} {:?}, {}ms",
+            probe,
+            url.url,
             res,
             now.elapsed().as_millis()
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MetricsConfig;
+
+    fn get_node_service(domain: serde_json::Value) -> NodeService {
+        let domain: Domain = serde_json::from_value(domain).unwrap();
+        NodeService::new(
+            HashMap::from([(domain.domain.clone(), domain)]),
+            Metrics::new(MetricsConfig::default()),
+            TrustedProxies::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_unverified_domain_is_not_ready() {
+        let node_service = get_node_service(serde_json::json!({
+            "domain": "localhost",
+            "chain_type": "ethereum",
+            "chain_identity": {"expected": "0x1"},
+            "urls": [{"url": "https://a.com"}],
+        }));
+        node_service.set_listening(true);
+        // the upstream answers polls, but its identity has not been confirmed yet
+        node_service.results.lock().await.insert(
+            "localhost".to_string(),
+            vec![NodeResult {
+                url: Url {
+                    url: "https://a.com".to_string(),
+                    ..Default::default()
+                },
+                block_number: 1,
+                latency: 10,
+            }],
+        );
+
+        assert!(!node_service.is_ready().await);
+        let domains = node_service.get_domains_readiness().await;
+        assert!(!domains[0].verified);
+        assert!(!domains[0].ready);
+    }
+}
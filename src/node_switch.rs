@@ -0,0 +1,122 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::request_url::redact_url;
+
+const NODE_SWITCH_HISTORY_SIZE: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NodeSwitchReason {
+    Behind { blocks: u64 },
+    PollFailure,
+    ManualPin,
+    HealthEjection { check: String },
+}
+
+impl NodeSwitchReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Behind { .. } => "behind",
+            Self::PollFailure => "poll_failure",
+            Self::ManualPin => "manual_pin",
+            Self::HealthEjection { .. } => "health_ejection",
+        }
+    }
+}
+
+impl fmt::Display for NodeSwitchReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Behind { blocks } => write!(f, "behind by {} blocks", blocks),
+            Self::HealthEjection { check } => write!(f, "failed {} check", check),
+            _ => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeSwitchEvent {
+    pub domain: String,
+    pub old_url: String,
+    pub new_url: String,
+    pub reason: NodeSwitchReason,
+    pub timestamp: u64,
+}
+
+impl NodeSwitchEvent {
+    pub fn new(domain: &str, old_url: &str, new_url: &str, reason: NodeSwitchReason) -> Self {
+        Self {
+            domain: domain.to_string(),
+            old_url: redact_url(old_url),
+            new_url: redact_url(new_url),
+            reason,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_secs())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NodeSwitchHistory {
+    events: Arc<Mutex<VecDeque<NodeSwitchEvent>>>,
+    capacity: usize,
+}
+
+impl Default for NodeSwitchHistory {
+    fn default() -> Self {
+        Self::new(NODE_SWITCH_HISTORY_SIZE)
+    }
+}
+
+impl NodeSwitchHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            events: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    pub async fn add(&self, event: NodeSwitchEvent) {
+        let mut events = self.events.lock().await;
+        if events.len() >= self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    pub async fn get_events(&self) -> Vec<NodeSwitchEvent> {
+        self.events.lock().await.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_history_is_bounded() {
+        let history = NodeSwitchHistory::new(2);
+        for index in 0..3 {
+            history
+                .add(NodeSwitchEvent::new(
+                    "localhost",
+                    "https://a.com",
+                    &format!("https://{}.com", index),
+                    NodeSwitchReason::PollFailure,
+                ))
+                .await;
+        }
+        let events = history.get_events().await;
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].new_url, "https://1.com");
+        assert_eq!(events[1].new_url, "https://2.com");
+    }
+}
@@ -0,0 +1,168 @@
+use std::error::Error;
+use std::fmt;
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{header, Response, StatusCode};
+use serde_json::{json, Value};
+
+use crate::json_rpc::RequestId;
+
+#[derive(Debug)]
+pub enum ProxyError {
+    MissingHost,
+    UnsupportedDomain(String),
+    InvalidUrl(String),
+    InvalidHeader(String),
+    RequestBody(Box<dyn Error + Send + Sync>),
+    RequestBodyTooLarge,
+    UpstreamUnverified,
+    NoUpstream,
+    UpstreamUnavailable(Box<dyn Error + Send + Sync>),
+    UpstreamBody(hyper::Error),
+    UpstreamDecode(std::io::Error),
+    NoConsensus,
+}
+
+impl ProxyError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::MissingHost => "missing_host",
+            Self::UnsupportedDomain(_) => "unsupported_domain",
+            Self::InvalidUrl(_) => "invalid_url",
+            Self::InvalidHeader(_) => "invalid_header",
+            Self::RequestBody(_) => "request_body",
+            Self::RequestBodyTooLarge => "request_body_too_large",
+            Self::UpstreamUnverified => "upstream_unverified",
+            Self::NoUpstream => "no_upstream",
+            Self::UpstreamUnavailable(_) => "upstream_unavailable",
+            Self::UpstreamBody(_) => "upstream_body",
+            Self::UpstreamDecode(_) => "upstream_decode",
+            Self::NoConsensus => "no_consensus",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::MissingHost | Self::InvalidUrl(_) | Self::RequestBody(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            Self::RequestBodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::UnsupportedDomain(_) => StatusCode::NOT_FOUND,
+            Self::InvalidHeader(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::UpstreamUnverified | Self::NoUpstream => StatusCode::SERVICE_UNAVAILABLE,
+            Self::UpstreamUnavailable(_)
+            | Self::UpstreamBody(_)
+            | Self::UpstreamDecode(_)
+            | Self::NoConsensus => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    // https://www.jsonrpc.org/specification#error_object
+    fn json_rpc_code(&self) -> i64 {
+        match self {
+            Self::MissingHost
+            | Self::InvalidUrl(_)
+            | Self::RequestBody(_)
+            | Self::RequestBodyTooLarge => -32600,
+            Self::UnsupportedDomain(_) => -32601,
+            Self::InvalidHeader(_) => -32603,
+            Self::UpstreamUnverified
+            | Self::NoUpstream
+            | Self::UpstreamUnavailable(_)
+            | Self::UpstreamBody(_)
+            | Self::UpstreamDecode(_)
+            | Self::NoConsensus => -32000,
+        }
+    }
+
+    pub fn as_response(&self, json_rpc: bool, id: &RequestId) -> Response<Full<Bytes>> {
+        let (content_type, body) = if json_rpc {
+            let error = json!({
+                "code": self.json_rpc_code(),
+                "message": self.to_string(),
+            });
+            let response = |id: &Value| json!({"jsonrpc": "2.0", "id": id, "error": error});
+            let body = match id {
+                RequestId::Single(id) => response(id),
+                RequestId::Batch(ids) => Value::Array(ids.iter().map(response).collect()),
+            };
+            ("application/json", body.to_string())
+        } else {
+            ("text/plain", self.to_string())
+        };
+
+        Response::builder()
+            .status(self.status())
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Full::new(Bytes::from(body)))
+            .unwrap()
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingHost => write!(f, "missing host header"),
+            Self::UnsupportedDomain(host) => write!(f, "unsupported domain: {}", host),
+            Self::InvalidUrl(url) => write!(f, "invalid url: {}", url),
+            Self::InvalidHeader(name) => write!(f, "invalid header: {}", name),
+            Self::RequestBody(_) => write!(f, "invalid request body"),
+            Self::RequestBodyTooLarge => write!(f, "request body too large"),
+            Self::UpstreamUnverified => write!(f, "upstream chain identity not verified"),
+            Self::NoUpstream => write!(f, "no upstream available"),
+            Self::UpstreamUnavailable(_) => write!(f, "upstream unavailable"),
+            Self::UpstreamBody(_) => write!(f, "upstream body error"),
+            Self::UpstreamDecode(_) => write!(f, "upstream body could not be decoded"),
+            Self::NoConsensus => write!(f, "upstreams did not reach consensus"),
+        }
+    }
+}
+
+impl Error for ProxyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::UpstreamUnavailable(err) | Self::RequestBody(err) => Some(err.as_ref()),
+            Self::UpstreamBody(err) => Some(err),
+            Self::UpstreamDecode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    async fn get_body(response: Response<Full<Bytes>>) -> Value {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_json_rpc_response_echoes_id() {
+        let id = RequestId::Single(json!(7));
+        let body = get_body(ProxyError::NoUpstream.as_response(true, &id)).await;
+        assert_eq!(body["id"], json!(7));
+        assert_eq!(body["error"]["code"], json!(-32000));
+    }
+
+    #[tokio::test]
+    async fn test_json_rpc_batch_response() {
+        let id = RequestId::Batch(vec![json!(1), json!("b")]);
+        let response = ProxyError::NoConsensus.as_response(true, &id);
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+        let body = get_body(response).await;
+        let ids: Vec<&Value> = body.as_array().unwrap().iter().map(|x| &x["id"]).collect();
+        assert_eq!(ids, vec![&json!(1), &json!("b")]);
+        assert!(body[1]["error"]["message"].is_string());
+    }
+
+    #[test]
+    fn test_request_body_too_large() {
+        let response = ProxyError::RequestBodyTooLarge.as_response(false, &RequestId::default());
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
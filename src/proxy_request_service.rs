@@ -1,33 +1,117 @@
 use bytes::Bytes;
-use http_body_util::{BodyExt, Full};
-use hyper::header::{self, HeaderName};
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::header::{self, HeaderName, HeaderValue};
 use hyper::service::Service;
 use hyper::HeaderMap;
 
-use futures::FutureExt;
-use hyper::{body::Incoming as IncomingBody, Request, Response};
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::{future, FutureExt};
+use hyper::{body::Incoming as IncomingBody, Method, Request, Response, StatusCode, Uri};
 use hyper_tls::HttpsConnector;
 use hyper_util::client::legacy::Client;
 use std::collections::HashMap;
 use std::future::Future;
+use std::net::IpAddr;
 use std::pin::Pin;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Instant;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration};
 
-use crate::config::Url;
+use crate::client_address::{ClientAddress, TrustedProxies};
+use crate::compression;
+use crate::config::{
+    Broadcast, Compression, Consensus, ContentEncoding, Domain, ErrorRule, HeaderPolicy,
+    HeaderRules, Hedge, Sticky, StickyKey, Url,
+};
+use crate::header_policy;
+use crate::json_rpc;
 use crate::logger::{log_incoming_request, log_proxy_response};
 use crate::metrics::Metrics;
+use crate::proxy_error::ProxyError;
 use crate::request_url::RequestUrl;
+use crate::sticky_session::StickySessions;
+use crate::upstream_errors::UpstreamErrors;
 
 #[derive(Debug, Clone)]
 pub struct ProxyRequestService {
-    pub domains: HashMap<String, NodeDomain>,
+    pub nodes: Arc<Mutex<HashMap<String, NodeDomain>>>,
+    pub domain_configs: Arc<HashMap<String, Domain>>,
     pub metrics: Metrics,
+    pub sessions: StickySessions,
+    pub upstream_errors: UpstreamErrors,
+    pub trusted_proxies: TrustedProxies,
+    pub remote_ip: IpAddr,
 }
 
 #[derive(Debug, Clone)]
 pub struct NodeDomain {
     pub url: Url,
+    pub verified: bool,
+    pub urls: Vec<Url>,
+    pub block_number: Option<u64>,
+}
+
+impl NodeDomain {
+    // Current node first, followed by the remaining healthy upstreams
+    pub fn get_upstreams(&self, count: usize) -> Vec<Url> {
+        let mut upstreams = vec![self.url.clone()];
+        upstreams.extend(self.urls.iter().filter(|x| **x != self.url).cloned());
+        upstreams.truncate(count.max(1));
+        upstreams
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProxyRequest {
+    pub host: String,
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    pub json_rpc: bool,
+    pub methods: Vec<String>,
+    pub client: String,
+    pub client_address: ClientAddress,
+}
+
+impl ProxyRequest {
+    // JSON-RPC method names for metric labels, REST paths are labelled separately
+    fn get_metric_methods(&self) -> &[String] {
+        if self.json_rpc {
+            &self.methods
+        } else {
+            &[]
+        }
+    }
+
+    fn get_metric_method(&self) -> &str {
+        match self.get_metric_methods() {
+            [] => "",
+            [method] => method,
+            _ => "batch",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProxyResponse {
+    pub url: Url,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    pub error: Option<ErrorRule>,
+}
+
+impl ProxyResponse {
+    pub fn is_success(&self) -> bool {
+        self.status.is_success() && self.error.is_none()
+    }
+
+    fn is_retry(&self) -> bool {
+        self.error.as_ref().is_some_and(|x| x.is_retry())
+    }
 }
 
 impl Service<Request<IncomingBody>> for ProxyRequestService {
@@ -37,120 +121,800 @@ impl Service<Request<IncomingBody>> for ProxyRequestService {
 
     fn call(&self, req: Request<IncomingBody>) -> Self::Future {
         let headers = req.headers().clone();
-        
+
         let host = headers
-            .get("host")
-            .expect("invalid host")
-            .to_str()
-            .unwrap_or_default();
+            .get(header::HOST)
+            .map(|x| x.to_str().unwrap_or_default())
+            .unwrap_or_default()
+            .to_string();
 
         let user_agent = headers
             .get("user-agent")
             .map(|x| x.to_str().unwrap_or_default())
             .unwrap_or_default().to_string();
 
-        log_incoming_request(&req);
+        let client_address = self
+            .trusted_proxies
+            .get_client_address(self.remote_ip, &headers);
+        log_incoming_request(&req, client_address.ip);
 
-        match self.domains.get(host) {
-            Some(domain) => {
-                let url = domain.url.clone();
-                let url = RequestUrl::from_uri(
-                    url.clone(),
-                    url.urls_override.clone().unwrap_or_default(),
-                    req.uri(),
-                );
+        let json_rpc = self
+            .domain_configs
+            .get(&host)
+            .map(|x| x.is_json_rpc())
+            .unwrap_or_default();
+        let metrics = self.metrics.clone();
+        let client = metrics.get_client_group(&headers);
+
+        let service = self.clone();
+        async move {
+            let now = Instant::now();
+            // Resolved per request, keep-alive connections outlive node switches
+            let node_domain = match service.get_node_domain(&host).await {
+                Ok(node_domain) => node_domain,
+                Err(err) => {
+                    return Ok(Self::error_response(
+                        &metrics,
+                        service.get_metric_host(&host),
+                        &user_agent,
+                        &client,
+                        err,
+                        json_rpc,
+                        &json_rpc::RequestId::default(),
+                    ));
+                }
+            };
+            let (parts, body) = req.into_parts();
+            let max_body_bytes = service
+                .domain_configs
+                .get(&host)
+                .map(|x| x.get_max_request_body_bytes())
+                .unwrap_or_default();
+            let body = match Limited::new(body, max_body_bytes).collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(err) => {
+                    let err = if err.is::<LengthLimitError>() {
+                        ProxyError::RequestBodyTooLarge
+                    } else {
+                        ProxyError::RequestBody(err)
+                    };
+                    return Ok(Self::error_response(
+                        &metrics,
+                        &host,
+                        &user_agent,
+                        &client,
+                        err,
+                        json_rpc,
+                        &json_rpc::RequestId::default(),
+                    ));
+                }
+            };
+            let (methods, id) = if json_rpc {
+                (
+                    json_rpc::get_methods(&body),
+                    json_rpc::get_request_id(&body),
+                )
+            } else {
+                (
+                    vec![parts.uri.path().to_string()],
+                    json_rpc::RequestId::default(),
+                )
+            };
+            let request = ProxyRequest {
+                host: host.clone(),
+                method: parts.method,
+                uri: parts.uri,
+                headers: parts.headers,
+                body,
+                json_rpc,
+                methods,
+                client: client.clone(),
+                client_address,
+            };
+            metrics.add_proxy_request(
+                &host,
+                &user_agent,
+                &client,
+                request.get_metric_methods(),
+                request.body.len(),
+            );
+
+            let response = match service.proxy(&request, &node_domain).await {
+                Ok(response) => {
+                    service
+                        .proxy_pass_response(&host, &request.headers, response)
+                        .await
+                }
+                Err(err) => {
+                    Self::error_response(&metrics, &host, &user_agent, &client, err, json_rpc, &id)
+                }
+            };
+            metrics.add_proxy_request_latency(
+                &host,
+                request.get_metric_method(),
+                &client,
+                response.status().as_u16(),
+                now.elapsed().as_millis(),
+            );
+            Ok(response)
+        }
+        .boxed()
+    }
+}
 
-                self.metrics.add_proxy_request(host, &user_agent);
+impl ProxyRequestService {
+    // Host headers are client controlled, only configured domains become metric labels
+    fn get_metric_host<'a>(&self, host: &'a str) -> &'a str {
+        if self.domain_configs.contains_key(host) {
+            host
+        } else {
+            "unknown"
+        }
+    }
 
-                let metrics = self.metrics.clone();
-                let host = host.to_string();
+    async fn get_node_domain(&self, host: &str) -> Result<NodeDomain, ProxyError> {
+        if host.is_empty() {
+            return Err(ProxyError::MissingHost);
+        }
+        let domain = self
+            .nodes
+            .lock()
+            .await
+            .get(host)
+            .cloned()
+            .ok_or_else(|| ProxyError::UnsupportedDomain(host.to_string()))?;
+        if !domain.verified {
+            return Err(ProxyError::UpstreamUnverified);
+        }
+        Ok(domain)
+    }
 
-                async move {
-                    let now = Instant::now();
+    async fn proxy(
+        &self,
+        request: &ProxyRequest,
+        node_domain: &NodeDomain,
+    ) -> Result<ProxyResponse, ProxyError> {
+        let methods = &request.methods;
+        let config = self.domain_configs.get(&request.host);
+        let node_domain = &match config.and_then(|x| x.sticky.as_ref()) {
+            Some(sticky) => {
+                self.get_sticky_node_domain(request, node_domain, sticky)
+                    .await
+            }
+            None => node_domain.clone(),
+        };
+        let node_domain = &match json_rpc::get_block_number(&request.body) {
+            Some(block_number) if request.json_rpc => {
+                Self::get_block_node_domain(request, node_domain, block_number)
+            }
+            _ => node_domain.clone(),
+        };
+        let broadcast = config.and_then(|x| x.get_broadcast(&request.method, methods));
+        if let Some(broadcast) = broadcast {
+            return self.proxy_broadcast(request, node_domain, broadcast).await;
+        }
+        let consensus = config
+            .and_then(|x| x.consensus.as_ref())
+            .filter(|x| x.is_enabled(methods));
+        // REST writes are never hedged, whatever the path patterns say
+        let hedge = config
+            .and_then(|x| x.hedge.as_ref())
+            .filter(|_| request.json_rpc || request.method == Method::GET)
+            .filter(|x| x.is_enabled(&request.method, methods));
 
-                    let response = Self::proxy_pass_get_data(req, url.clone()).await?;
+        match (consensus, hedge) {
+            (Some(consensus), _) => self.proxy_consensus(request, node_domain, consensus).await,
+            (None, Some(hedge)) => self.proxy_hedge(request, node_domain, hedge).await,
+            (None, None) => {
+                let retries = match config.and_then(|x| x.error_classification.as_ref()) {
+                    Some(classification)
+                        if methods
+                            .iter()
+                            .all(|x| json_rpc::is_idempotent(&request.method, x)) =>
+                    {
+                        classification.get_max_retries()
+                    }
+                    _ => 0,
+                };
+                self.proxy_retry(request, node_domain, retries).await
+            }
+        }
+    }
 
-                    log_proxy_response(&url, &response, now.elapsed().as_millis());
+    async fn proxy_retry(
+        &self,
+        request: &ProxyRequest,
+        node_domain: &NodeDomain,
+        retries: usize,
+    ) -> Result<ProxyResponse, ProxyError> {
+        let mut result = Err(ProxyError::NoUpstream);
+        for url in node_domain.get_upstreams(retries + 1) {
+            result = self.proxy_pass_get_data(request, &url).await;
+            if !result.as_ref().is_ok_and(|x| x.is_retry()) {
+                break;
+            }
+        }
+        result
+    }
 
-                    metrics.add_proxy_response(
-                        host.as_str(),
-                        url.uri.path(),
-                        url.uri.host().unwrap_or_default(),
-                        response.status().as_u16(),
-                        now.elapsed().as_millis(),
-                    );
+    async fn get_sticky_node_domain(
+        &self,
+        request: &ProxyRequest,
+        node_domain: &NodeDomain,
+        sticky: &Sticky,
+    ) -> NodeDomain {
+        let key = match sticky.key {
+            StickyKey::ClientIp => Some(request.client_address.ip.to_string()),
+            StickyKey::ApiKey | StickyKey::Header => request
+                .headers
+                .get(sticky.get_header())
+                .and_then(|x| x.to_str().ok())
+                .map(|x| x.to_string()),
+        };
+        let Some(key) = key else {
+            return node_domain.clone();
+        };
+
+        let url = self
+            .sessions
+            .get_url(
+                &request.host,
+                &key,
+                Duration::from_secs(sticky.get_ttl_seconds()),
+                &node_domain.urls,
+                &node_domain.url,
+            )
+            .await;
+        NodeDomain {
+            url,
+            ..node_domain.clone()
+        }
+    }
+
+    // Restricts upstreams to those retaining the requested block height
+    fn get_block_node_domain(
+        request: &ProxyRequest,
+        node_domain: &NodeDomain,
+        block_number: u64,
+    ) -> NodeDomain {
+        let Some(latest_block) = node_domain.block_number else {
+            return node_domain.clone();
+        };
+        let urls: Vec<Url> = node_domain
+            .urls
+            .iter()
+            .filter(|x| x.can_serve_block(block_number, latest_block))
+            .cloned()
+            .collect();
+        if node_domain.url.can_serve_block(block_number, latest_block) {
+            return NodeDomain {
+                urls,
+                ..node_domain.clone()
+            };
+        }
+        let Some(url) = urls.first().cloned() else {
+            return node_domain.clone();
+        };
+        println!(
+            "proxy service: {} block {} routed to {}",
+            request.host, block_number, url.url
+        );
+        NodeDomain {
+            url,
+            urls,
+            ..node_domain.clone()
+        }
+    }
 
-                    Self::proxy_pass_response(response).await
+    async fn proxy_broadcast(
+        &self,
+        request: &ProxyRequest,
+        node_domain: &NodeDomain,
+        broadcast: &Broadcast,
+    ) -> Result<ProxyResponse, ProxyError> {
+        // spawned so the remaining submissions complete after the first acceptance is returned
+        let mut tasks: FuturesUnordered<_> = node_domain
+            .get_upstreams(broadcast.get_upstreams())
+            .into_iter()
+            .map(|url| {
+                let service = self.clone();
+                let request = request.clone();
+                tokio::spawn(async move { service.proxy_pass_get_data(&request, &url).await })
+            })
+            .collect();
+
+        let chain_type = self
+            .domain_configs
+            .get(&request.host)
+            .map(|x| x.chain_type.as_str())
+            .unwrap_or_default();
+        let mut already_known: Option<ProxyResponse> = None;
+        let mut last_result = Err(ProxyError::NoUpstream);
+        while let Some(result) = tasks.next().await {
+            let result = match result {
+                Ok(result) => result,
+                Err(err) => Err(ProxyError::UpstreamUnavailable(Box::new(err))),
+            };
+            match result {
+                Ok(response) if broadcast.is_already_known(&response.body) => {
+                    already_known.get_or_insert(response);
+                }
+                Ok(response)
+                    if response.status.is_success()
+                        && broadcast.is_accepted(chain_type, &response.body) =>
+                {
+                    self.metrics
+                        .add_proxy_broadcast_accepted(&request.host, &response.url.url);
+                    return Ok(response);
                 }
-                .boxed()
+                result => last_result = result,
             }
-            _ => async move {
-                Ok(Response::builder()
-                    .body(Full::new(Bytes::from("unsupported domain")))
-                    .unwrap())
+        }
+
+        // Nobody accepted it but some upstream already had it, so it was submitted before.
+        // The client gets that upstream's "already known" body as is, there is no generic
+        // way to derive the success answer (e.g. the tx hash) for every chain
+        match already_known {
+            Some(response) => {
+                self.metrics
+                    .add_proxy_broadcast_accepted(&request.host, &response.url.url);
+                Ok(response)
             }
-            .boxed(),
+            None => last_result,
         }
     }
-}
 
-impl ProxyRequestService {
+    async fn proxy_hedge(
+        &self,
+        request: &ProxyRequest,
+        node_domain: &NodeDomain,
+        hedge: &Hedge,
+    ) -> Result<ProxyResponse, ProxyError> {
+        let primary = self.proxy_pass_get_data(request, &node_domain.url);
+        let Some(next) = node_domain.get_upstreams(2).into_iter().nth(1) else {
+            return primary.await;
+        };
+        let delay = hedge
+            .percentile
+            .and_then(|x| self.metrics.get_proxy_latency_percentile(&request.host, x))
+            .map(|x| x as u64)
+            .unwrap_or(hedge.get_delay_ms());
+
+        let mut primary = std::pin::pin!(primary);
+        tokio::select! {
+            result = &mut primary => return result,
+            _ = sleep(Duration::from_millis(delay)) => {}
+        }
+
+        // first successful answer wins, dropping the other request cancels it
+        let mut secondary = std::pin::pin!(self.proxy_pass_get_data(request, &next));
+        let result = tokio::select! {
+            result = &mut primary => match result {
+                Ok(response) if response.is_success() => Ok(response),
+                _ => secondary.await,
+            },
+            result = &mut secondary => match result {
+                Ok(response) if response.is_success() => Ok(response),
+                _ => primary.await,
+            },
+        };
+
+        let winner = match &result {
+            Ok(response) if response.url == next => "hedge",
+            Ok(_) => "primary",
+            Err(_) => "none",
+        };
+        self.metrics
+            .add_proxy_hedge(&request.host, &next.url, winner);
+        result
+    }
+
+    async fn proxy_consensus(
+        &self,
+        request: &ProxyRequest,
+        node_domain: &NodeDomain,
+        consensus: &Consensus,
+    ) -> Result<ProxyResponse, ProxyError> {
+        let upstreams = node_domain.get_upstreams(consensus.upstreams);
+        let responses = future::join_all(
+            upstreams
+                .iter()
+                .map(|url| self.proxy_pass_get_data(request, url)),
+        )
+        .await;
+
+        let mut votes: Vec<(String, Vec<ProxyResponse>)> = vec![];
+        for response in responses {
+            let response = match response {
+                Ok(response) if response.is_success() => response,
+                Ok(response) => {
+                    println!(
+                        "proxy service: {} consensus vote from {} rejected, status: {}",
+                        request.host, response.url.url, response.status
+                    );
+                    continue;
+                }
+                Err(err) => {
+                    println!(
+                        "proxy service: {} consensus vote failed, error: {:?}",
+                        request.host, err
+                    );
+                    continue;
+                }
+            };
+            let key = if request.json_rpc {
+                json_rpc::normalize_response(&response.body)
+            } else {
+                String::from_utf8_lossy(&response.body).to_string()
+            };
+            match votes.iter_mut().find(|(x, _)| *x == key) {
+                Some((_, group)) => group.push(response),
+                None => votes.push((key, vec![response])),
+            }
+        }
+
+        // ties resolve to the group containing the earliest ranked upstream
+        let majority = votes
+            .into_iter()
+            .rev()
+            .map(|(_, group)| group)
+            .max_by_key(|group| group.len())
+            .unwrap_or_default();
+
+        for url in upstreams
+            .iter()
+            .filter(|url| !majority.iter().any(|x| &x.url == *url))
+        {
+            self.metrics
+                .add_proxy_consensus_disagreement(&request.host, &url.url);
+        }
+
+        if majority.len() < consensus.get_quorum() {
+            return Err(ProxyError::NoConsensus);
+        }
+        majority.into_iter().next().ok_or(ProxyError::NoConsensus)
+    }
+
+    fn error_response(
+        metrics: &Metrics,
+        host: &str,
+        user_agent: &str,
+        client: &str,
+        err: ProxyError,
+        json_rpc: bool,
+        id: &json_rpc::RequestId,
+    ) -> Response<Full<Bytes>> {
+        println!("proxy service: {} error: {:?}", host, err);
+        metrics.add_proxy_error(host, err.name(), user_agent, client);
+        err.as_response(json_rpc, id)
+    }
+
+    // Domain rules first so the Url's own rules take precedence
+    fn get_header_rules<'a>(
+        &'a self,
+        host: &str,
+        url: &'a Url,
+        direction: fn(&HeaderPolicy) -> Option<&HeaderRules>,
+    ) -> Vec<&'a HeaderRules> {
+        let domain = self
+            .domain_configs
+            .get(host)
+            .and_then(|x| x.header_policy.as_ref());
+        [domain, url.header_policy.as_ref()]
+            .into_iter()
+            .flatten()
+            .filter_map(direction)
+            .collect()
+    }
+
+    // Encoded upstream bodies pass through when the client accepts the encoding
+    // and nothing needs to read them
+    fn is_decode_required(&self, request: &ProxyRequest, encoding: ContentEncoding) -> bool {
+        let config = self.domain_configs.get(&request.host);
+        let accept_encoding = request
+            .headers
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or_default();
+        compression::negotiate(accept_encoding, &[encoding]).is_none()
+            || config.is_some_and(|x| {
+                x.error_classification.is_some()
+                    || x.get_broadcast(&request.method, &request.methods).is_some()
+                    || x.consensus
+                        .as_ref()
+                        .is_some_and(|x| x.is_enabled(&request.methods))
+            })
+            || (request.json_rpc && !self.metrics.is_admitted(&request.host, &request.methods))
+    }
+
+    fn get_compression(&self, host: &str) -> Compression {
+        self.domain_configs
+            .get(host)
+            .map(|x| x.get_compression())
+            .unwrap_or_default()
+    }
+
     async fn proxy_pass_response(
-        response: Response<IncomingBody>,
-    ) -> Result<Response<Full<Bytes>>, Box<dyn std::error::Error + Send + Sync>> {
-        let keep_headers = vec![header::CONTENT_TYPE, header::CONTENT_ENCODING];
+        &self,
+        host: &str,
+        request_headers: &HeaderMap,
+        response: ProxyResponse,
+    ) -> Response<Full<Bytes>> {
+        let rules = self.get_header_rules(host, &response.url, |x| x.response.as_ref());
+        let mut headers = header_policy::filter_headers(
+            &response.headers,
+            header_policy::RESPONSE_HEADERS,
+            &rules,
+        );
+        header_policy::set_headers(&mut headers, &rules);
 
-        let resp_headers = response.headers().clone();
-        let status =  response.status();
-        let body = response.collect().await?.to_bytes();
+        let mut body = response.body;
+        let compression = self.get_compression(host);
+        let is_encoded = headers.contains_key(header::CONTENT_ENCODING);
+        if compression.is_client_enabled() || is_encoded {
+            headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        }
+        if compression.is_client_enabled() && !is_encoded {
+            let accept_encoding = request_headers
+                .get(header::ACCEPT_ENCODING)
+                .and_then(|x| x.to_str().ok())
+                .unwrap_or_default();
+            let encoding = compression::negotiate(accept_encoding, &compression.get_encodings())
+                .filter(|_| body.len() >= compression.get_min_bytes());
+            // falls back to the identity body if encoding fails
+            let encoded = match encoding {
+                Some(encoding) => compression::spawn_encode(encoding, body.clone())
+                    .await
+                    .ok()
+                    .map(|x| (encoding, x)),
+                None => None,
+            };
+            if let Some((encoding, encoded)) = encoded {
+                headers.insert(
+                    header::CONTENT_ENCODING,
+                    HeaderValue::from_static(encoding.as_str()),
+                );
+                body = Bytes::from(encoded);
+            }
+        }
 
         let mut new_response = Response::new(Full::from(body));
-        *new_response.status_mut() = status;
-        *new_response.headers_mut() = Self::persist_headers(&resp_headers, &keep_headers);
+        *new_response.status_mut() = response.status;
+        *new_response.headers_mut() = headers;
 
-        Ok(new_response)
+        new_response
     }
 
     async fn proxy_pass_get_data(
-        original_request: Request<IncomingBody>,
-        url: RequestUrl,
-    ) -> Result<Response<IncomingBody>, Box<dyn std::error::Error + Send + Sync>> {
+        &self,
+        original_request: &ProxyRequest,
+        url: &Url,
+    ) -> Result<ProxyResponse, ProxyError> {
+        let request_url = RequestUrl::from_uri(
+            url.clone(),
+            url.urls_override.clone().unwrap_or_default(),
+            &original_request.uri,
+        )?;
+
         let client =
             Client::builder(hyper_util::rt::TokioExecutor::new()).build(HttpsConnector::new());
 
-        let keep_headers = vec![header::CONTENT_TYPE, header::CONTENT_ENCODING];
+        let rules = self.get_header_rules(&original_request.host, url, |x| x.request.as_ref());
 
         // request
-        let original_headers = original_request.headers().clone();
         let mut request = Request::builder()
-            .method(original_request.method())
-            .uri(url.clone().uri)
-            .body(original_request.into_body())
-            .expect("invalid request params");
+            .method(original_request.method.clone())
+            .uri(request_url.uri.clone())
+            .body(Full::new(original_request.body.clone()))
+            .map_err(|_| ProxyError::InvalidUrl(request_url.uri.path().to_string()))?;
 
         // append url params
-        let mut new_headers = Self::persist_headers(&original_headers, &keep_headers);
-        for (key, value) in url.params.clone() {
-            new_headers.append(
-                HeaderName::from_str(&key).unwrap(),
-                value.clone().parse().unwrap(),
+        let mut new_headers = header_policy::filter_headers(
+            &original_request.headers,
+            header_policy::REQUEST_HEADERS,
+            &rules,
+        );
+        for (key, value) in request_url.params.clone() {
+            let name =
+                HeaderName::from_str(&key).map_err(|_| ProxyError::InvalidHeader(key.clone()))?;
+            let value = value
+                .parse()
+                .map_err(|_| ProxyError::InvalidHeader(key.clone()))?;
+            new_headers.append(name, value);
+        }
+        if let Some(forwarded_headers) = &url.forwarded_headers {
+            new_headers.extend(
+                original_request
+                    .client_address
+                    .get_forwarded_headers(&original_request.host, forwarded_headers),
             );
         }
+        let compression = self.get_compression(&original_request.host);
+        if compression.is_upstream_enabled() {
+            let encodings: Vec<&str> = compression
+                .get_encodings()
+                .iter()
+                .map(|x| x.as_str())
+                .collect();
+            if let Ok(value) = HeaderValue::from_str(&encodings.join(", ")) {
+                new_headers.insert(header::ACCEPT_ENCODING, value);
+            }
+        }
+        header_policy::set_headers(&mut new_headers, &rules);
         *request.headers_mut() = new_headers;
 
-        Ok(client.request(request).await?)
-    }
+        let now = Instant::now();
+        let response = client
+            .request(request)
+            .await
+            .map_err(|err| ProxyError::UpstreamUnavailable(Box::new(err)))?;
+        let latency = now.elapsed().as_millis();
 
-    pub fn persist_headers(headers: &HeaderMap, list: &[HeaderName]) -> HeaderMap {
-        headers
-            .iter()
-            .filter_map(|(k, v)| {
-                if list.contains(&k) {
-                    Some((k.clone(), v.clone()))
-                } else {
-                    None
-                }
-            })
+        log_proxy_response(&request_url, response.status(), latency);
+
+        let mut headers = response.headers().clone();
+        let status = response.status();
+        let body = response
             .collect()
+            .await
+            .map_err(ProxyError::UpstreamBody)?
+            .to_bytes();
+        let size = body.len();
+
+        // bodies are inspected and compared decoded, metrics use the wire size
+        let encoding = headers
+            .get(header::CONTENT_ENCODING)
+            .and_then(|x| x.to_str().ok())
+            .and_then(compression::parse_content_encoding)
+            .filter(|x| self.is_decode_required(original_request, *x));
+        let body = match encoding {
+            Some(encoding) => {
+                let body = compression::spawn_decode(encoding, body)
+                    .await
+                    .map_err(ProxyError::UpstreamDecode)?;
+                headers.remove(header::CONTENT_ENCODING);
+                headers.remove(header::CONTENT_LENGTH);
+                Bytes::from(body)
+            }
+            None => body,
+        };
+
+        let host = original_request.host.as_str();
+        if original_request.json_rpc && status.is_success() && !json_rpc::is_method_not_found(&body)
+        {
+            self.metrics.admit_methods(host, &original_request.methods);
+        }
+        self.metrics.add_proxy_response(
+            host,
+            original_request.uri.path(),
+            request_url.uri.host().unwrap_or_default(),
+            original_request.get_metric_method(),
+            &original_request.client,
+            status.as_u16(),
+            latency,
+            size,
+        );
+
+        let error = self
+            .domain_configs
+            .get(host)
+            .and_then(|x| x.error_classification.as_ref())
+            .and_then(|x| x.classify(&body));
+        if let Some(rule) = &error {
+            println!(
+                "proxy service: {} upstream {} error: {}",
+                host, url.url, rule.name
+            );
+            self.metrics.add_proxy_upstream_error(
+                host,
+                &url.url,
+                &rule.name,
+                &original_request.client,
+            );
+            self.upstream_errors.add(host, &url.url).await;
+        }
+
+        Ok(ProxyResponse {
+            url: url.clone(),
+            status,
+            headers,
+            body,
+            error,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+    use tokio::net::TcpListener;
+
+    // Upstream answering every request with the same body
+    async fn upstream(body: &'static str) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::task::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service_fn(move |_: Request<IncomingBody>| async move {
+                    Ok::<_, hyper::Error>(Response::new(Full::new(Bytes::from(body))))
+                });
+                tokio::task::spawn(
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+        Url {
+            url,
+            ..Default::default()
+        }
+    }
+
+    async fn broadcast(bodies: &[&'static str]) -> ProxyResponse {
+        let mut urls = vec![];
+        for body in bodies {
+            urls.push(upstream(body).await);
+        }
+        let domain: Domain = serde_json::from_value(serde_json::json!({
+            "domain": "localhost",
+            "chain_type": "ethereum",
+            "broadcast": {},
+            "urls": urls.iter().map(|x| serde_json::json!({"url": x.url})).collect::<Vec<_>>(),
+        }))
+        .unwrap();
+        let service = ProxyRequestService {
+            nodes: Arc::new(Mutex::new(HashMap::new())),
+            domain_configs: Arc::new(HashMap::from([("localhost".to_string(), domain.clone())])),
+            metrics: Metrics::new(Default::default()),
+            sessions: StickySessions::default(),
+            upstream_errors: UpstreamErrors::default(),
+            trusted_proxies: TrustedProxies::default(),
+            remote_ip: IpAddr::from([127, 0, 0, 1]),
+        };
+        let request = ProxyRequest {
+            host: "localhost".to_string(),
+            method: Method::POST,
+            uri: Uri::from_static("/"),
+            headers: HeaderMap::new(),
+            body: Bytes::from_static(
+                br#"{"jsonrpc":"2.0","id":1,"method":"eth_sendRawTransaction","params":["0x02"]}"#,
+            ),
+            json_rpc: true,
+            methods: vec!["eth_sendRawTransaction".to_string()],
+            client: String::new(),
+            client_address: TrustedProxies::default()
+                .get_client_address(IpAddr::from([127, 0, 0, 1]), &HeaderMap::new()),
+        };
+        let node_domain = NodeDomain {
+            url: urls[0].clone(),
+            verified: true,
+            urls,
+            block_number: None,
+        };
+        service
+            .proxy_broadcast(&request, &node_domain, domain.broadcast.as_ref().unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_prefers_accepted() {
+        let response = broadcast(&[
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"already known"}}"#,
+            r#"{"jsonrpc":"2.0","id":1,"result":"0xab"}"#,
+        ])
+        .await;
+        assert_eq!(response.body, r#"{"jsonrpc":"2.0","id":1,"result":"0xab"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_already_known() {
+        let known = r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"already known"}}"#;
+        let response = broadcast(&[known, known]).await;
+        assert!(response.status.is_success());
+        assert_eq!(response.body, known);
     }
 }
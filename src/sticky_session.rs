@@ -0,0 +1,215 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::BuildHasher;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+use crate::config::Url;
+
+// Keys are client supplied, the oldest sessions are evicted beyond this
+const MAX_SESSIONS: usize = 100_000;
+const PURGE_INTERVAL: Duration = Duration::from_secs(30);
+
+// Host and a hash of the key, so credentials like x-api-key are never held
+type SessionKey = (String, u64);
+
+#[derive(Debug, Clone)]
+struct StickySession {
+    url: Url,
+    expires_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Sessions {
+    sessions: HashMap<SessionKey, StickySession>,
+    // ordered by expiry, for purging and for evicting the oldest when full
+    expiries: BTreeSet<(Instant, SessionKey)>,
+}
+
+impl Sessions {
+    fn insert(&mut self, key: SessionKey, session: StickySession, max_sessions: usize) {
+        match self.sessions.remove(&key) {
+            Some(previous) => {
+                self.expiries.remove(&(previous.expires_at, key.clone()));
+            }
+            None if self.sessions.len() >= max_sessions => {
+                if let Some((_, oldest)) = self.expiries.pop_first() {
+                    self.sessions.remove(&oldest);
+                }
+            }
+            None => {}
+        }
+        self.expiries.insert((session.expires_at, key.clone()));
+        self.sessions.insert(key, session);
+    }
+
+    fn purge(&mut self, now: Instant) {
+        while self.expiries.first().is_some_and(|x| x.0 <= now) {
+            if let Some((_, key)) = self.expiries.pop_first() {
+                self.sessions.remove(&key);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StickySessions {
+    sessions: Arc<Mutex<Sessions>>,
+    hasher: RandomState,
+    max_sessions: usize,
+}
+
+impl Default for StickySessions {
+    fn default() -> Self {
+        Self::new(MAX_SESSIONS)
+    }
+}
+
+impl StickySessions {
+    pub fn new(max_sessions: usize) -> Self {
+        Self {
+            sessions: Arc::new(Mutex::new(Sessions::default())),
+            hasher: RandomState::new(),
+            max_sessions,
+        }
+    }
+
+    // Keeps the session upstream while it is still eligible, otherwise rebinds to the fallback
+    pub async fn get_url(
+        &self,
+        host: &str,
+        key: &str,
+        ttl: Duration,
+        eligible: &[Url],
+        fallback: &Url,
+    ) -> Url {
+        let now = Instant::now();
+        let session_key = (host.to_string(), self.hasher.hash_one(key));
+        let mut sessions = self.sessions.lock().await;
+
+        let url = match sessions.sessions.get(&session_key) {
+            Some(session) if session.expires_at > now && eligible.contains(&session.url) => {
+                session.url.clone()
+            }
+            Some(session) => {
+                if session.expires_at > now {
+                    println!(
+                        "proxy service: {} sticky upstream {} unavailable, moving to {}",
+                        host, session.url.url, fallback.url
+                    );
+                }
+                fallback.clone()
+            }
+            None => fallback.clone(),
+        };
+        sessions.insert(
+            session_key,
+            StickySession {
+                url: url.clone(),
+                expires_at: now + ttl,
+            },
+            self.max_sessions,
+        );
+        url
+    }
+
+    // Drops expired sessions in the background instead of on the request path
+    pub fn start(&self, mut shutdown: watch::Receiver<bool>) -> JoinHandle<()> {
+        let sessions = self.sessions.clone();
+        tokio::task::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = sleep(PURGE_INTERVAL) => sessions.lock().await.purge(Instant::now()),
+                    _ = shutdown.changed() => break,
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(value: &str) -> Url {
+        Url {
+            url: value.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sticky_session() {
+        let sessions = StickySessions::default();
+        let ttl = Duration::from_secs(60);
+        let (a, b) = (url("https://a.com"), url("https://b.com"));
+
+        let first = sessions
+            .get_url("localhost", "key", ttl, &[a.clone(), b.clone()], &a)
+            .await;
+        assert_eq!(first, a);
+
+        let second = sessions
+            .get_url("localhost", "key", ttl, &[a.clone(), b.clone()], &b)
+            .await;
+        assert_eq!(second, a);
+
+        let third = sessions
+            .get_url("localhost", "key", ttl, std::slice::from_ref(&b), &b)
+            .await;
+        assert_eq!(third, b);
+    }
+
+    #[tokio::test]
+    async fn test_sticky_session_limit() {
+        let sessions = StickySessions::new(2);
+        let ttl = Duration::from_secs(60);
+        let (a, b) = (url("https://a.com"), url("https://b.com"));
+        let eligible = [a.clone(), b.clone()];
+
+        let short_ttl = Duration::from_secs(30);
+        sessions
+            .get_url("localhost", "1", short_ttl, &eligible, &a)
+            .await;
+        sessions.get_url("localhost", "2", ttl, &eligible, &a).await;
+        sessions.get_url("localhost", "3", ttl, &eligible, &b).await;
+        assert_eq!(sessions.sessions.lock().await.sessions.len(), 2);
+
+        let third = sessions.get_url("localhost", "3", ttl, &eligible, &a).await;
+        assert_eq!(third, b);
+        // the session expiring first was evicted and rebinds to the fallback
+        let first = sessions.get_url("localhost", "1", ttl, &eligible, &b).await;
+        assert_eq!(first, b);
+    }
+
+    #[tokio::test]
+    async fn test_sticky_session_purge() {
+        let sessions = StickySessions::default();
+        let a = url("https://a.com");
+        sessions
+            .get_url(
+                "localhost",
+                "1",
+                Duration::ZERO,
+                std::slice::from_ref(&a),
+                &a,
+            )
+            .await;
+        sessions
+            .get_url(
+                "localhost",
+                "2",
+                Duration::from_secs(60),
+                std::slice::from_ref(&a),
+                &a,
+            )
+            .await;
+
+        sessions.sessions.lock().await.purge(Instant::now());
+        assert_eq!(sessions.sessions.lock().await.sessions.len(), 1);
+    }
+}
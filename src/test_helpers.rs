@@ -0,0 +1,16 @@
+use std::str::FromStr;
+
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+
+pub fn headers(values: &[(&str, &str)]) -> HeaderMap {
+    values
+        .iter()
+        .map(|(name, value)| {
+            (
+                HeaderName::from_str(name).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            )
+        })
+        .collect()
+}
@@ -0,0 +1,27 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+
+// Classified error responses per domain and upstream, drained on every poll
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamErrors {
+    counts: Arc<Mutex<HashMap<String, HashMap<String, u64>>>>,
+}
+
+impl UpstreamErrors {
+    pub async fn add(&self, domain: &str, url: &str) {
+        *self
+            .counts
+            .lock()
+            .await
+            .entry(domain.to_string())
+            .or_default()
+            .entry(url.to_string())
+            .or_default() += 1;
+    }
+
+    pub async fn take(&self, domain: &str) -> HashMap<String, u64> {
+        self.counts.lock().await.remove(domain).unwrap_or_default()
+    }
+}
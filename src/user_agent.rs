@@ -0,0 +1,200 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+use regex::{Regex, RegexSet};
+
+use crate::config::UserAgentPatterns;
+
+const UNKNOWN_USER_AGENT: &str = "unknown";
+const OTHER_VERSION: &str = "other";
+const MAX_VERSION_PARTS: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct UserAgentClassifier {
+    set: RegexSet,
+    categories: Vec<String>,
+    version: Option<Regex>,
+    max_versions: usize,
+    versions: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Default for UserAgentClassifier {
+    fn default() -> Self {
+        Self {
+            set: RegexSet::empty(),
+            categories: vec![],
+            version: None,
+            max_versions: 0,
+            versions: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+}
+
+impl UserAgentClassifier {
+    pub fn new(config: &UserAgentPatterns) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut categories = vec![];
+        let mut patterns = vec![];
+        for (category, values) in config.get_rules() {
+            for pattern in values {
+                Regex::new(&pattern).map_err(|err| {
+                    format!(
+                        "invalid user agent pattern {:?} for {}: {}",
+                        pattern, category, err
+                    )
+                })?;
+                categories.push(category.clone());
+                patterns.push(pattern);
+            }
+        }
+
+        let version = match &config.version {
+            Some(version) => {
+                let regex = Regex::new(&version.pattern).map_err(|err| {
+                    format!(
+                        "invalid user agent version pattern {:?}: {}",
+                        version.pattern, err
+                    )
+                })?;
+                if regex.captures_len() < 2 {
+                    return Err(format!(
+                        "user agent version pattern {:?} needs a capture group",
+                        version.pattern
+                    )
+                    .into());
+                }
+                Some(regex)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            set: RegexSet::new(&patterns)?,
+            categories,
+            version,
+            max_versions: config
+                .version
+                .as_ref()
+                .map(|x| x.get_max_versions())
+                .unwrap_or_default(),
+            versions: Arc::new(Mutex::new(HashSet::new())),
+        })
+    }
+
+    // First matching rule wins, in configuration order
+    pub fn categorize(&self, user_agent: &str) -> String {
+        self.set
+            .matches(user_agent)
+            .iter()
+            .next()
+            .map(|index| self.categories[index].clone())
+            .unwrap_or_else(|| UNKNOWN_USER_AGENT.to_string())
+    }
+
+    // Dotted numbers like 1.2.3, anything else could fill the version slots with junk
+    fn is_version(version: &str) -> bool {
+        let parts: Vec<&str> = version.split('.').collect();
+        parts.len() <= MAX_VERSION_PARTS
+            && parts
+                .iter()
+                .all(|x| !x.is_empty() && x.chars().all(|c| c.is_ascii_digit()))
+    }
+
+    // Versions beyond max_versions, or not shaped like one, are folded into "other"
+    pub fn get_version(&self, user_agent: &str) -> String {
+        let Some(version) = self
+            .version
+            .as_ref()
+            .and_then(|x| x.captures(user_agent))
+            .and_then(|x| x.get(1))
+            .map(|x| x.as_str())
+        else {
+            return String::new();
+        };
+        if !Self::is_version(version) {
+            return OTHER_VERSION.to_string();
+        }
+        let mut versions = self.versions.lock().unwrap();
+        if versions.contains(version) || versions.len() < self.max_versions {
+            versions.insert(version.to_string());
+            version.to_string()
+        } else {
+            OTHER_VERSION.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{UserAgentRule, UserAgentVersion};
+
+    #[test]
+    fn test_categorize() {
+        let classifier = UserAgentClassifier::new(&UserAgentPatterns {
+            rules: vec![
+                UserAgentRule {
+                    name: "ios".to_string(),
+                    patterns: vec!["Gem.*Darwin".to_string()],
+                },
+                UserAgentRule {
+                    name: "gem".to_string(),
+                    patterns: vec!["Gem".to_string()],
+                },
+            ],
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            classifier.categorize("Gem/1.2.3 CFNetwork Darwin/23"),
+            "ios"
+        );
+        assert_eq!(classifier.categorize("Gem/1.2.3 okhttp/4.12"), "gem");
+        assert_eq!(classifier.categorize("curl/8.0"), "unknown");
+    }
+
+    #[test]
+    fn test_get_version() {
+        let classifier = UserAgentClassifier::new(&UserAgentPatterns {
+            version: Some(UserAgentVersion {
+                pattern: r"Gem/(\d+\.\d+\.\d+)".to_string(),
+                max_versions: Some(1),
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(classifier.get_version("Gem/1.2.3 Darwin"), "1.2.3");
+        assert_eq!(classifier.get_version("Gem/1.2.4 Darwin"), "other");
+        assert_eq!(classifier.get_version("curl/8.0"), "");
+    }
+
+    #[test]
+    fn test_get_version_charset() {
+        let classifier = UserAgentClassifier::new(&UserAgentPatterns {
+            version: Some(UserAgentVersion {
+                pattern: r"Gem/(\S+)".to_string(),
+                max_versions: Some(1),
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(classifier.get_version("Gem/1.2.3.4.5 Darwin"), "other");
+        assert_eq!(classifier.get_version("Gem/1.2\"} Darwin"), "other");
+        assert_eq!(classifier.get_version("Gem/1..2 Darwin"), "other");
+        // junk never took the only slot
+        assert_eq!(classifier.get_version("Gem/1.2 Darwin"), "1.2");
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let err = UserAgentClassifier::new(&UserAgentPatterns {
+            rules: vec![UserAgentRule {
+                name: "ios".to_string(),
+                patterns: vec!["Gem(".to_string()],
+            }],
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("invalid user agent pattern"));
+    }
+}